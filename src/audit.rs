@@ -0,0 +1,340 @@
+//! Audit trail of `sudo` invocations.
+//!
+//! Every attempt at running a command through `sudo` is appended to a log file,
+//! `/var/log/sudo.log` unless the policy says otherwise, one record per line. A
+//! record names the invoking user, its terminal and working directory, the target
+//! user, the full command line and what happened:
+//!
+//! - `accept`: the policy allowed the command and the user authenticated.
+//! - `reject`: the policy does not allow the command.
+//! - `auth_failure`: the user could not authenticate.
+//! - `exit`: the command finished, with its exit status.
+//! - `empty_password`: the user has no password, and the policy let the user in
+//!   without one or refused.
+//!
+//! The records of a command whose session is recorded also give the id of the
+//! session, see `iolog`.
+//!
+//! Records are written either in the classic text format:
+//!
+//! ```text
+//! 2018-03-04T10:11:12Z : alice : TTY=/dev/pts/1 ; PWD=/home/alice ; USER=root ; COMMAND=/bin/ls -l ; EVENT=accept
+//! ```
+//!
+//! or as JSON Lines:
+//!
+//! ```text
+//! {"time":"2018-03-04T10:11:12Z","user":"alice","tty":"/dev/pts/1","cwd":"/home/alice","target":"root","command":["/bin/ls","-l"],"event":"accept"}
+//! ```
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use current_tty;
+
+/// Default location of the log.
+pub const AUDIT_LOG: &'static str = "/var/log/sudo.log";
+
+/// How records are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One `key=value` list per line.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}'", s))
+        }
+    }
+}
+
+/// What a record is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The command was allowed.
+    Accept,
+    /// The policy refused the command.
+    Reject,
+    /// The invoking user did not authenticate.
+    AuthFailure,
+    /// The command finished with the given exit status.
+    Exit(i32),
+    /// The user has no password and was let in when true, refused otherwise.
+    EmptyPassword(bool),
+}
+
+impl Event {
+    /// Name of the event in the records.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::Accept => "accept",
+            Event::Reject => "reject",
+            Event::AuthFailure => "auth_failure",
+            Event::Exit(_) => "exit",
+            Event::EmptyPassword(_) => "empty_password"
+        }
+    }
+}
+
+/// One line of the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Seconds since the UNIX epoch.
+    pub time: u64,
+    /// The invoking user.
+    pub user: String,
+    /// The terminal of the invoking user, if any.
+    pub tty: Option<String>,
+    /// Working directory of the invoking user.
+    pub cwd: String,
+    /// The target, as `user` or `user:group`, empty when there is none.
+    pub target: String,
+    /// The command and its arguments.
+    pub command: Vec<String>,
+    /// The id of the recorded session, when the command is recorded.
+    pub session: Option<String>,
+    /// What happened.
+    pub event: Event,
+}
+
+impl Record {
+    /// A record of `event` for `user`, taking the time, terminal and working
+    /// directory from the current process.
+    pub fn new(user: &str, event: Event) -> Record {
+        Record {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0),
+            user: user.to_string(),
+            tty: current_tty(),
+            cwd: env::current_dir().map(|dir| dir.to_string_lossy().into_owned()).unwrap_or_default(),
+            target: String::new(),
+            command: Vec::new(),
+            session: None,
+            event: event,
+        }
+    }
+
+    /// Formats the record in the classic text format.
+    ///
+    /// Control characters are escaped, so that a command line can not forge
+    /// records.
+    pub fn to_text(&self) -> String {
+        let mut line = format!("{} : {} : TTY={} ; PWD={} ; USER={} ; COMMAND={} ; EVENT={}",
+                               format_time(self.time), text_escape(&self.user),
+                               text_escape(self.tty.as_ref().map_or("unknown", |tty| tty.as_str())),
+                               text_escape(&self.cwd), text_escape(&self.target),
+                               text_escape(&self.command.join(" ")), self.event.name());
+        if let Some(ref session) = self.session {
+            line.push_str(&format!(" ; TSID={}", session));
+        }
+        match self.event {
+            Event::Exit(status) => line.push_str(&format!(" ; STATUS={}", status)),
+            Event::EmptyPassword(allowed) => line.push_str(&format!(" ; ALLOWED={}", if allowed { "yes" } else { "no" })),
+            _ => ()
+        }
+        line
+    }
+
+    /// Formats the record as a JSON object.
+    pub fn to_json(&self) -> String {
+        let command = self.command.iter()
+            .map(|arg| json_string(arg))
+            .collect::<Vec<String>>()
+            .join(",");
+        let mut line = format!("{{\"time\":{},\"user\":{},\"tty\":{},\"cwd\":{},\"target\":{},\"command\":[{}],\"event\":{}",
+                               json_string(&format_time(self.time)), json_string(&self.user),
+                               self.tty.as_ref().map_or("null".to_string(), |tty| json_string(tty)),
+                               json_string(&self.cwd), json_string(&self.target), command,
+                               json_string(self.event.name()));
+        if let Some(ref session) = self.session {
+            line.push_str(&format!(",\"session\":{}", json_string(session)));
+        }
+        match self.event {
+            Event::Exit(status) => line.push_str(&format!(",\"status\":{}", status)),
+            Event::EmptyPassword(allowed) => line.push_str(&format!(",\"allowed\":{}", allowed)),
+            _ => ()
+        }
+        line.push('}');
+        line
+    }
+}
+
+/// The log file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditLog {
+    path: PathBuf,
+    format: LogFormat,
+}
+
+impl AuditLog {
+    /// Writes records to `path` in `format`.
+    pub fn new<P: AsRef<Path>>(path: P, format: LogFormat) -> AuditLog {
+        AuditLog {
+            path: path.as_ref().to_path_buf(),
+            format: format,
+        }
+    }
+
+    /// Path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `record` to the log, creating it readable by root only when missing.
+    ///
+    /// Each record goes out in a single write, so records of concurrent
+    /// invocations do not interleave.
+    pub fn write(&self, record: &Record) -> io::Result<()> {
+        let mut line = match self.format {
+            LogFormat::Text => record.to_text(),
+            LogFormat::Json => record.to_json()
+        };
+        line.push('\n');
+
+        let mut file = OpenOptions::new().append(true).create(true).mode(0o600).open(&self.path)?;
+        file.write_all(line.as_bytes())
+    }
+}
+
+/// Formats seconds since the UNIX epoch as an ISO 8601 UTC date and time.
+pub fn format_time(time: u64) -> String {
+    let days = (time / 86400) as i64;
+    let secs = time % 86400;
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Escapes the control characters of `s` as `\xNN`.
+fn text_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        if c.is_control() {
+            escaped.push_str(&format!("\\x{:02x}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(event: Event) -> Record {
+        Record {
+            time: 1520158272,
+            user: "alice".to_string(),
+            tty: Some("/dev/pts/1".to_string()),
+            cwd: "/home/alice".to_string(),
+            target: "root".to_string(),
+            command: vec!["/bin/ls".to_string(), "-l".to_string()],
+            session: None,
+            event: event,
+        }
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(format_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_time(1520158272), "2018-03-04T10:11:12Z");
+        assert_eq!(format_time(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_time(951868799), "2000-02-29T23:59:59Z");
+        assert_eq!(format_time(4107542400), "2100-03-01T00:00:00Z");
+    }
+
+    #[test]
+    fn text_records() {
+        assert_eq!(record(Event::Accept).to_text(),
+                   "2018-03-04T10:11:12Z : alice : TTY=/dev/pts/1 ; PWD=/home/alice ; USER=root ; COMMAND=/bin/ls -l ; EVENT=accept");
+
+        let mut record = record(Event::Exit(3));
+        record.tty = None;
+        record.session = Some("000012".to_string());
+        assert_eq!(record.to_text(),
+                   "2018-03-04T10:11:12Z : alice : TTY=unknown ; PWD=/home/alice ; USER=root ; COMMAND=/bin/ls -l ; EVENT=exit ; TSID=000012 ; STATUS=3");
+        assert!(self::record(Event::EmptyPassword(false)).to_text().ends_with("EVENT=empty_password ; ALLOWED=no"));
+    }
+
+    #[test]
+    fn text_records_can_not_be_forged() {
+        assert_eq!(text_escape("plain; text"), "plain; text");
+        assert_eq!(text_escape("a\nb\rc\td\x1b[2Je\x7f"), "a\\x0ab\\x0dc\\x09d\\x1b[2Je\\x7f");
+
+        let mut record = record(Event::Reject);
+        record.command = vec!["/bin/ls".to_string(), "\n2018-03-04T10:11:12Z : root : EVENT=accept".to_string()];
+        record.cwd = "/tmp/\r".to_string();
+        let line = record.to_text();
+        assert_eq!(line.lines().count(), 1);
+        assert!(! line.contains('\r'));
+        assert!(line.contains("COMMAND=/bin/ls \\x0a2018-03-04T10:11:12Z : root : EVENT=accept ; EVENT=reject"), "{}", line);
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string(""), "\"\"");
+        assert_eq!(json_string("caf\u{e9}"), "\"caf\u{e9}\"");
+        assert_eq!(json_string("\"quoted\" \\ path"), "\"\\\"quoted\\\" \\\\ path\"");
+        assert_eq!(json_string("a\nb\rc\td\x1be\x7f"), "\"a\\nb\\rc\\td\\u001be\x7f\"");
+    }
+
+    #[test]
+    fn json_records() {
+        assert_eq!(record(Event::Accept).to_json(),
+                   "{\"time\":\"2018-03-04T10:11:12Z\",\"user\":\"alice\",\"tty\":\"/dev/pts/1\",\"cwd\":\"/home/alice\",\
+                    \"target\":\"root\",\"command\":[\"/bin/ls\",\"-l\"],\"event\":\"accept\"}");
+
+        let mut record = record(Event::EmptyPassword(true));
+        record.tty = None;
+        record.session = Some("000012".to_string());
+        record.command = vec!["/bin/echo".to_string(), "\"}\n".to_string()];
+        let line = record.to_json();
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.contains("\"tty\":null"));
+        assert!(line.contains("\"command\":[\"/bin/echo\",\"\\\"}\\n\"]"), "{}", line);
+        assert!(line.ends_with(",\"event\":\"empty_password\",\"session\":\"000012\",\"allowed\":true}"), "{}", line);
+        assert!(self::record(Event::Exit(1)).to_json().ends_with(",\"event\":\"exit\",\"status\":1}"));
+    }
+}
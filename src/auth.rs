@@ -0,0 +1,164 @@
+//! Password authentication shared by `login`, `su`, `sudo` and `passwd`.
+
+use std::io::{self, BufRead, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use termion::input::TermRead;
+
+use db::User;
+
+/// Number of password attempts given by the utilities.
+pub const MAX_ATTEMPTS: u32 = 3;
+/// Time to wait after each failed attempt.
+pub const FAIL_DELAY: Duration = Duration::from_secs(2);
+
+/// Outcome of an `authenticate` call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthResult {
+    /// The right password was entered.
+    Success,
+    /// Every attempt was used up without entering the right password.
+    WrongPassword,
+    /// The user has no password set, nothing was prompted.
+    NoPassword,
+    /// The password could not be read, the input was closed or failed.
+    Aborted,
+    /// Refused by the policy, see `AuthStack`.
+    Denied,
+}
+
+/// Where passwords are read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PasswordSource {
+    /// The terminal, without echo, see `read_password`.
+    Terminal,
+    /// A line of stdin, for passwords fed by a pipe. The prompt goes to stderr.
+    Stdin,
+    /// The first line printed by a helper program, run with the prompt as its
+    /// argument and the given uid and gid.
+    Askpass {
+        program: String,
+        uid: u32,
+        gid: u32,
+    },
+    /// Nowhere, for runs that must not prompt. Reading fails as if the input was
+    /// closed, so that only what needs no password can succeed.
+    NonInteractive,
+}
+
+impl PasswordSource {
+    /// Reads a password after showing `prompt`.
+    ///
+    /// Returns `None` when the input was closed, or the helper failed, before a
+    /// password was entered.
+    pub fn read(&self, prompt: &str) -> io::Result<Option<String>> {
+        match *self {
+            PasswordSource::Terminal => read_password(prompt),
+            PasswordSource::Stdin => {
+                eprint!("{}", prompt);
+
+                let stdin = io::stdin();
+                let mut line = String::new();
+                if BufRead::read_line(&mut stdin.lock(), &mut line)? == 0 {
+                    return Ok(None);
+                }
+                Ok(Some(line.trim_end_matches(|c| c == '\n' || c == '\r').to_string()))
+            },
+            PasswordSource::Askpass { ref program, uid, gid } => {
+                let output = Command::new(program)
+                    .arg(prompt)
+                    .uid(uid)
+                    .gid(gid)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::inherit())
+                    .output()?;
+                if ! output.status.success() {
+                    return Ok(None);
+                }
+
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                Ok(stdout.lines().next().map(|line| line.to_string()))
+            },
+            PasswordSource::NonInteractive => Ok(None)
+        }
+    }
+}
+
+/// Prompts for `user`'s password and checks it.
+///
+/// `user` must carry its real hash, see `AccountDb::with_shadow`.
+///
+/// The prompt is written to stdout and the password read from stdin without echo.
+/// Up to `attempts` passwords are read. After each wrong one this waits for `delay`
+/// and, when there are attempts left, prints `Sorry, try again.` to stderr.
+///
+/// # Examples
+///
+/// ```no_run
+/// use userutils::{AccountDb, AuthResult, FileDb, FAIL_DELAY, MAX_ATTEMPTS, authenticate};
+///
+/// let db = FileDb::default();
+/// let user = db.with_shadow(db.user_by_name("goyox86").unwrap());
+/// match authenticate(&user, "password: ", MAX_ATTEMPTS, FAIL_DELAY) {
+///     AuthResult::Success => println!("welcome"),
+///     _ => println!("go away")
+/// }
+/// ```
+pub fn authenticate(user: &User, prompt: &str, attempts: u32, delay: Duration) -> AuthResult {
+    match prompt_password(user, &PasswordSource::Terminal, prompt, attempts, delay) {
+        Ok(_) => AuthResult::Success,
+        Err(result) => result
+    }
+}
+
+/// Same as `authenticate`, but reads the passwords from `source` and hands the
+/// verified one back on success.
+///
+/// Any other outcome is returned as the error.
+pub fn prompt_password(user: &User, source: &PasswordSource, prompt: &str, attempts: u32, delay: Duration)
+                       -> Result<String, AuthResult> {
+    if user.hash.is_empty() {
+        return Err(AuthResult::NoPassword);
+    }
+
+    for attempt in 1..attempts + 1 {
+        let password = match source.read(prompt) {
+            Ok(Some(password)) => password,
+            Ok(None) | Err(_) => return Err(AuthResult::Aborted)
+        };
+
+        if user.verify_passwd(&password) {
+            return Ok(password);
+        }
+
+        thread::sleep(delay);
+        if attempt < attempts {
+            eprintln!("Sorry, try again.");
+        }
+    }
+
+    Err(AuthResult::WrongPassword)
+}
+
+/// Writes `prompt` to stdout and reads a password from stdin without echo.
+///
+/// Returns `None` when the input was closed before a password was entered.
+pub fn read_password(prompt: &str) -> io::Result<Option<String>> {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    stdout.write_all(prompt.as_bytes())?;
+    stdout.flush()?;
+
+    let password = stdin.read_passwd(&mut stdout)?;
+
+    stdout.write_all(b"\n")?;
+    stdout.flush()?;
+
+    Ok(password)
+}
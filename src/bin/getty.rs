@@ -1,9 +1,9 @@
 #![deny(warnings)]
 
-extern crate redox_termios;
 extern crate syscall;
 extern crate arg_parser;
 extern crate extra;
+extern crate userutils;
 
 use std::{env, process, str};
 use std::fs::File;
@@ -14,6 +14,7 @@ use std::process::{exit, Child, Command, Stdio};
 use arg_parser::ArgParser;
 use extra::io::fail;
 use extra::option::OptionalExt;
+use userutils::pty::getpty;
 
 const MAN_PAGE: &'static str = /* @MANSTART{getty} */ r#"
 NAME
@@ -103,25 +104,6 @@ pub fn handle(event_file: &mut File, tty_fd: RawFd, master_fd: RawFd, process: &
     process.wait().expect("getty: failed to wait on login");
 }
 
-pub fn getpty(columns: u32, lines: u32) -> (RawFd, String) {
-    use redox_termios;
-    use syscall;
-
-    let master = syscall::open("pty:", syscall::O_CLOEXEC | syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK).expect("getty: failed to create PTY");
-
-    if let Ok(winsize_fd) = syscall::dup(master, b"winsize") {
-        let _ = syscall::write(winsize_fd, &redox_termios::Winsize {
-            ws_row: lines as u16,
-            ws_col: columns as u16
-        });
-        let _ = syscall::close(winsize_fd);
-    }
-
-    let mut buf: [u8; 4096] = [0; 4096];
-    let count = syscall::fpath(master, &mut buf).unwrap();
-    (master, unsafe { String::from_utf8_unchecked(Vec::from(&buf[..count])) })
-}
-
 fn daemon(tty_fd: RawFd, clear: bool, stderr: &mut Stderr) {
     let (columns, lines) = {
         let mut path = [0; 4096];
@@ -137,7 +119,7 @@ fn daemon(tty_fd: RawFd, clear: bool, stderr: &mut Stderr) {
         }
     };
 
-    let (master_fd, pty) = getpty(columns, lines);
+    let (master_fd, pty) = getpty(columns, lines).expect("getty: failed to create PTY");
 
     let mut event_file = File::open("event:").expect("getty: failed to open event file");
 
@@ -2,7 +2,7 @@
 
 extern crate arg_parser;
 extern crate extra;
-extern crate redox_users;
+extern crate userutils;
 
 use extra::option::OptionalExt;
 
@@ -11,14 +11,14 @@ use std::io::Write;
 use std::process::exit;
 
 use arg_parser::ArgParser;
-use redox_users::{add_group, get_unique_group_id};
+use userutils::{AccountDb, FileDb, Group};
 
 const MAN_PAGE: &'static str = /* @MANSTART{groupadd} */ r#"
 NAME
     groupadd - add a user group
 
 SYNOPSIS
-    groupadd [ -f | --force ] group
+    groupadd [ -f | --force ] [ -R | --root root_dir ] group
     groupadd [ -h | --help ]
 
 DESCRIPTION
@@ -34,6 +34,10 @@ OPTIONS
     -h, --help
         Display this help and exit.
 
+    -R, --root root_dir
+        Add the group to the account database found under
+        root_dir instead of the one under /.
+
 AUTHOR
     Written by Wesley Hershberger.
 "#; /* @MANEND */
@@ -45,7 +49,8 @@ fn main() {
     
     let mut parser = ArgParser::new(1)
         .add_flag(&["h", "help"])
-        .add_flag(&["f", "force"]);
+        .add_flag(&["f", "force"])
+        .add_opt("R", "root");
     parser.parse(env::args());
     
     // Shows the help
@@ -62,15 +67,21 @@ fn main() {
         &parser.args[0]
     };
     
-    let gid = match get_unique_group_id() {
-        Some(gid) => gid,
-        None => {
-            eprintln!("groupadd: no available gid");
-            exit(1);
-        }
+    let db = match parser.get_opt("root") {
+        Some(root) => FileDb::new(root),
+        None => FileDb::default()
     };
-    
-    match add_group(groupname, gid, &[""]) {
+
+    // Held from the id allocation until the group is written, so that
+    // concurrent runs can not be handed the same id.
+    let lock = db.lock().unwrap_or_else(|err| {
+        eprintln!("groupadd: cannot lock account database: {}", err);
+        exit(1);
+    });
+    let result = add_group(&db, groupname);
+    drop(lock);
+
+    match result {
         Ok(_) => {},
         Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists && parser.found("force") => {
             exit(0);
@@ -81,3 +92,16 @@ fn main() {
         }
     }
 }
+
+fn add_group(db: &FileDb, groupname: &str) -> io::Result<()> {
+    let gid = db.unique_group_id()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no available gid"))?;
+
+    let group = Group {
+        group: groupname.to_string(),
+        gid: gid,
+        users: Vec::new(),
+    };
+
+    db.add_group(group)
+}
@@ -1,6 +1,7 @@
 extern crate arg_parser;
 extern crate extra;
 extern crate redox_users;
+extern crate userutils;
 
 use std::borrow::Borrow;
 use std::hash::Hash;
@@ -11,7 +12,8 @@ use std::process::exit;
 use extra::io::fail;
 use extra::option::OptionalExt;
 use arg_parser::{ArgParser, Param};
-use redox_users::{get_egid, get_gid, get_euid, get_uid, get_user_by_id, get_group_by_id};
+use redox_users::{get_egid, get_gid, get_euid, get_uid};
+use userutils::{AccountDb, FileDb};
 
 const HELP_INFO: &'static str = "Try ‘id --help’ for more information.\n";
 const MAN_PAGE: &'static str = /* @MANSTART{id} */ r#"
@@ -74,6 +76,8 @@ pub fn main() {
         .add_flag(&["r"]);
     parser.parse(env::args());
 
+    let db = FileDb::default();
+
     // Shows the help
     if parser.found("help") {
         print_msg(MAN_PAGE, &mut stdout, &mut stderr);
@@ -120,7 +124,7 @@ pub fn main() {
             get_euid()
         };
 
-        get_user_by_id(uid).map(|user| {
+        db.user_by_id(uid as u32).map(|user| {
             print_msg(&format!("{}\n", user.user), &mut stdout, &mut stderr);
             exit(0);
         }).or_else(|| {
@@ -151,7 +155,7 @@ pub fn main() {
             get_egid()
         };
 
-        get_group_by_id(gid).map(|group| {
+        db.group_by_id(gid as u32).map(|group| {
             print_msg(&format!("{}\n", group.group), &mut stdout, &mut stderr);
             exit(0);
         }).or_else(|| {
@@ -188,11 +192,11 @@ pub fn main() {
     // We get everything we can and show that
     let euid = get_euid();
     let egid = get_egid();
-    let user = get_user_by_id(euid).unwrap_or_else(|| {
+    let user = db.user_by_id(euid as u32).unwrap_or_else(|| {
         fail(&format!("id: no user found for uid: {}", euid), &mut stderr);
     });
 
-    let group = get_group_by_id(egid).unwrap_or_else(|| {
+    let group = db.group_by_id(egid as u32).unwrap_or_else(|| {
         fail(&format!("id: no group found for gid: {}", euid), &mut stderr);
     });
 
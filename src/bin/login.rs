@@ -3,7 +3,6 @@
 extern crate arg_parser;
 extern crate extra;
 extern crate liner;
-extern crate termion;
 extern crate redox_users;
 extern crate userutils;
 
@@ -15,9 +14,8 @@ use std::str;
 
 use extra::option::OptionalExt;
 use arg_parser::ArgParser;
-use termion::input::TermRead;
-use redox_users::get_user_by_name;
-use userutils::spawn_shell;
+use redox_users::get_uid;
+use userutils::{AccountDb, AuthContext, AuthResult, AuthStack, EnvPolicy, FileDb, PasswordSource, User, exec_shell};
 
 const MAN_PAGE: &'static str = /* @MANSTART{login} */ r#"
 NAME
@@ -57,6 +55,16 @@ pub fn main() {
         exit(0);
     }
 
+    let db = FileDb::default();
+    let stack = AuthStack::load("login").unwrap_or_else(|err| {
+        eprintln!("login: {}", err);
+        exit(1);
+    });
+    let env = EnvPolicy::load().unwrap_or_else(|err| {
+        eprintln!("login: {}", err);
+        exit(1);
+    });
+
     if let Ok(mut issue) = File::open(ISSUE_FILE) {
         io::copy(&mut issue, &mut stdout).try(&mut stderr);
         stdout.flush().try(&mut stderr);
@@ -68,10 +76,7 @@ pub fn main() {
             .try(&mut stderr);
 
         if ! user.is_empty() {
-            let stdin = io::stdin();
-            let mut stdin = stdin.lock();
-
-            let user_option = get_user_by_name(user);
+            let user_option = db.user_by_name(&user).map(|user| db.with_shadow(user));
             match user_option {
                 None => {
                     stdout.write(b"\nLogin incorrect\n").try(&mut stderr);
@@ -80,30 +85,36 @@ pub fn main() {
                     continue;
                 },
                 Some(user) => {
-                    if user.hash == "" {
-                        if let Ok(mut motd) = File::open(MOTD_FILE) {
-                            io::copy(&mut motd, &mut stdout).try(&mut stderr);
-                            stdout.flush().try(&mut stderr);
-                        }
-
-                        spawn_shell(user);
-                        break;
-                    }
-
-                    stdout.write_all(b"\x1B[1mpassword:\x1B[0m ").try(&mut stderr);
-                    stdout.flush().try(&mut stderr);
-                    if let Some(password) = stdin.read_passwd(&mut stdout).try(&mut stderr) {
-                        stdout.write(b"\n").try(&mut stderr);
-                        stdout.flush().try(&mut stderr);
+                    let ctx = AuthContext {
+                        user: &user,
+                        caller: get_uid() as u32,
+                        prompt: "\x1B[1mpassword:\x1B[0m ".to_string(),
+                        source: PasswordSource::Terminal,
+                        upgrade: Some(&db),
+                    };
+
+                    match stack.authenticate(&ctx) {
+                        AuthResult::Success => {
+                            if stack.open_session(&ctx) != AuthResult::Success {
+                                eprintln!("login: session refused for '{}'", user.user);
+                                exit(1);
+                            }
 
-                        if user.verify_passwd(&password) {
                             if let Ok(mut motd) = File::open(MOTD_FILE) {
                                 io::copy(&mut motd, &mut stdout).try(&mut stderr);
                                 stdout.flush().try(&mut stderr);
                             }
 
-                            spawn_shell(user);
-                            break;
+                            login(&db, &user, &env);
+                        },
+                        AuthResult::Aborted => {
+                            stdout.write(b"\n").try(&mut stderr);
+                            stdout.flush().try(&mut stderr);
+                        },
+                        _ => {
+                            stdout.write(b"\nLogin incorrect\n").try(&mut stderr);
+                            stdout.write(b"\n").try(&mut stderr);
+                            stdout.flush().try(&mut stderr);
                         }
                     }
                 }
@@ -114,3 +125,9 @@ pub fn main() {
         }
     }
 }
+
+fn login(db: &FileDb, user: &User, env: &EnvPolicy) -> ! {
+    let err = exec_shell(user, &db.user_groups(user), env);
+    eprintln!("login: failed to execute shell '{}': {}", user.shell, err);
+    exit(1);
+}
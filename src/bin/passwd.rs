@@ -2,26 +2,25 @@
 
 extern crate arg_parser;
 extern crate extra;
-extern crate rand;
-extern crate termion;
 extern crate redox_users;
+extern crate userutils;
 
-use rand::{Rng, OsRng};
 use std::{env, io};
 use std::io::Write;
 use std::process::exit;
 
 use arg_parser::ArgParser;
 use extra::option::OptionalExt;
-use termion::input::TermRead;
-use redox_users::{User, get_uid, get_user_by_id, get_user_by_name};
+use redox_users::get_uid;
+use userutils::{AccountDb, AuthContext, AuthResult, AuthStack, FileDb, HashParams, PasswordSource, read_password};
+use userutils::hash::hash_password;
 
 const MAN_PAGE: &'static str = /* @MANSTART{passwd} */ r#"
 NAME
     passwd - modify a user's password
 
 SYNOPSIS
-    passwd [ user ]
+    passwd [ -R | --root root_dir ] [ user ]
     passwd [ -h | --help ]
 
 DESCRIPTION
@@ -35,19 +34,23 @@ OPTIONS
     --help
         Display this help and exit.
 
+    -R
+    --root root_dir
+        Use the account database found under root_dir instead of the one
+        under /. Only the super-user may use this option.
+
 AUTHOR
     Written by Jeremy Soller, Jose Narvaez.
 "#; /* @MANEND */
 
 fn main() {
-    let stdin = io::stdin();
-    let mut stdin = stdin.lock();
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
     let mut stderr = io::stderr();
 
     let mut parser = ArgParser::new(1)
-        .add_flag(&["h", "help"]);
+        .add_flag(&["h", "help"])
+        .add_opt("R", "root");
     parser.parse(env::args());
 
     // Shows the help
@@ -58,15 +61,24 @@ fn main() {
     }
 
     let uid = get_uid();
-    
+
+    let db = match parser.get_opt("root") {
+        Some(ref root) if uid == 0 => FileDb::new(root),
+        Some(_) => {
+            eprintln!("passwd: only the super-user may use --root");
+            exit(1);
+        },
+        None => FileDb::default()
+    };
+
     let user = if parser.args.is_empty() {
-        get_user_by_id(uid).unwrap_or_else(|| {
+        db.user_by_id(uid as u32).map(|user| db.with_shadow(user)).unwrap_or_else(|| {
             eprintln!("passwd: current user id {} does not exist", uid);
             exit(1);
         })
     } else {
         let username = &parser.args[0];
-        get_user_by_name(username).unwrap_or_else(|| {
+        db.user_by_name(username).map(|user| db.with_shadow(user)).unwrap_or_else(|| {
             eprintln!("passwd: user '{}' does not exist", username);
             exit(1);
         })
@@ -78,42 +90,31 @@ fn main() {
         stdout.write_all(&msg.as_bytes()).try(&mut stderr);
         stdout.flush().try(&mut stderr);
 
-        let mut verified = false;
-        if user.hash == "" {
-            verified = true;
-        } else if user.uid == uid || uid != 0 {
-            stdout.write_all(b"current password: ").try(&mut stderr);
-            stdout.flush().try(&mut stderr);
-
-            if let Some(password) = stdin.read_passwd(&mut stdout).try(&mut stderr) {
-                stdout.write(b"\n").try(&mut stderr);
-                stdout.flush().try(&mut stderr);
-
-                if user.verify_passwd(&password) {
-                    verified = true;
-                }
-            }
-        } else {
-            verified = true;
-        }
-
-        if verified {
-            stdout.write_all(b"new password: ").try(&mut stderr);;
-            stdout.flush().try(&mut stderr);;
-
-            if let Some(new_password) = stdin.read_passwd(&mut stdout).try(&mut stderr) {
-                stdout.write(b"\nconfirm password: ").try(&mut stderr);
-                stdout.flush().try(&mut stderr);
+        let stack = AuthStack::load("passwd").unwrap_or_else(|err| {
+            eprintln!("passwd: {}", err);
+            exit(1);
+        });
+        let ctx = AuthContext {
+            user: &user,
+            caller: uid,
+            prompt: "current password: ".to_string(),
+            source: PasswordSource::Terminal,
+            upgrade: None,
+        };
+
+        if stack.authenticate(&ctx) == AuthResult::Success {
+            if let Some(new_password) = read_password("new password: ").try(&mut stderr) {
+                if let Some(confirm_password) = read_password("confirm password: ").try(&mut stderr) {
+                    if new_password == confirm_password {
+                        let params = HashParams::load().try(&mut stderr);
+                        let hash = hash_password(&new_password, &params).try(&mut stderr);
 
-                if let Some(confirm_password) = stdin.read_passwd(&mut stdout).try(&mut stderr) {
-                    stdout.write(b"\n").try(&mut stderr);
-                    stdout.flush().try(&mut stderr);;
+                        if let Err(err) = set_hash(&db, &user.user, hash) {
+                            eprintln!("passwd: failed to update password for '{}': {}", user.user, err);
+                            exit(1);
+                        }
 
-                    if new_password == confirm_password {
-                        let salt = format!("{:X}", OsRng::new().try(&mut stderr).next_u64());
-                        let encoded_passwd = User::encode_passwd(&new_password, &salt);
-                        // TODO: Actually persist the new password to PASSWD_FILE
-                        let msg = format!("{}\n", encoded_passwd);
+                        let msg = format!("password updated for '{}'\n", user.user);
                         stdout.write_all(&msg.as_bytes()).try(&mut stderr);
                         stdout.flush().try(&mut stderr);
                     } else {
@@ -129,7 +130,7 @@ fn main() {
                 exit(1);
             }
         } else {
-            eprintln!("passwd: incorrect current password");
+            eprintln!("passwd: authentication failed");
             exit(1);
         }
     } else {
@@ -137,3 +138,14 @@ fn main() {
         exit(1);
     }
 }
+
+/// Stores `hash` as the password of `name`, holding the database lock so that
+/// changes made to the entry since it was read are not lost.
+fn set_hash(db: &FileDb, name: &str, hash: String) -> io::Result<()> {
+    let _lock = db.lock()?;
+
+    let mut user = db.user_by_name(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "user does not exist"))?;
+    user.hash = hash;
+    db.update_user(&user)
+}
@@ -0,0 +1,107 @@
+#![deny(warnings)]
+
+extern crate arg_parser;
+extern crate extra;
+extern crate userutils;
+
+use extra::option::OptionalExt;
+
+use std::{io, env};
+use std::io::Write;
+use std::process::exit;
+
+use arg_parser::ArgParser;
+use userutils::{AccountDb, FileDb, Shadow};
+use userutils::db::SHADOWED;
+
+const MAN_PAGE: &'static str = /* @MANSTART{pwconv} */ r#"
+NAME
+    pwconv - move password hashes into the shadow file
+
+SYNOPSIS
+    pwconv [ -R | --root root_dir ]
+    pwconv [ -h | --help ]
+
+DESCRIPTION
+    The pwconv utility creates the shadow file if it does not exist yet,
+    moves the password hashes found in the passwd file into it and replaces
+    them with "x". Shadow entries of users that no longer exist are removed.
+
+    Running pwconv on an already converted database is harmless.
+
+OPTIONS
+    -h, --help
+        Display this help and exit.
+
+    -R, --root root_dir
+        Convert the account database found under root_dir instead
+        of the one under /.
+
+AUTHOR
+    Written by Jose Narvaez.
+"#; /* @MANEND */
+
+fn main() {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut stderr = io::stderr();
+
+    let mut parser = ArgParser::new(1)
+        .add_flag(&["h", "help"])
+        .add_opt("R", "root");
+    parser.parse(env::args());
+
+    // Shows the help
+    if parser.found("help") {
+        stdout.write_all(MAN_PAGE.as_bytes()).try(&mut stderr);
+        stdout.flush().try(&mut stderr);
+        exit(0);
+    }
+
+    let db = match parser.get_opt("root") {
+        Some(root) => FileDb::new(root),
+        None => FileDb::default()
+    };
+
+    let lock = db.lock().unwrap_or_else(|err| {
+        eprintln!("pwconv: cannot lock account database: {}", err);
+        exit(1);
+    });
+    let result = convert(&db);
+    drop(lock);
+
+    if let Err(msg) = result {
+        eprintln!("pwconv: {}", msg);
+        exit(1);
+    }
+}
+
+fn convert(db: &FileDb) -> Result<(), String> {
+    let mut users = db.users().map_err(|err| err.to_string())?;
+    let mut shadows = db.shadows().map_err(|err| err.to_string())?;
+
+    shadows.retain(|shadow| users.iter().any(|user| user.user == shadow.user));
+
+    for user in users.iter_mut() {
+        if user.hash == SHADOWED {
+            if ! shadows.iter().any(|shadow| shadow.user == user.user) {
+                eprintln!("pwconv: '{}' is shadowed but has no shadow entry", user.user);
+            }
+            continue;
+        }
+
+        match shadows.iter().position(|shadow| shadow.user == user.user) {
+            Some(i) => shadows[i].hash = user.hash.clone(),
+            None => shadows.push(Shadow::new(&user.user, &user.hash))
+        }
+        user.hash = SHADOWED.to_string();
+    }
+
+    // The shadow file goes first, so that a failure can never leave hashes behind
+    // that only the passwd file used to have.
+    db.save_shadows(&shadows)
+        .map_err(|err| format!("failed to write {}: {}", db.shadow_path().display(), err))?;
+
+    db.save_users(&users)
+        .map_err(|err| format!("failed to write {}: {}", db.passwd_path().display(), err))
+}
@@ -0,0 +1,96 @@
+#![deny(warnings)]
+
+extern crate arg_parser;
+extern crate extra;
+extern crate userutils;
+
+use extra::option::OptionalExt;
+
+use std::{fs, io, env};
+use std::io::Write;
+use std::process::exit;
+
+use arg_parser::ArgParser;
+use userutils::{AccountDb, FileDb};
+use userutils::db::SHADOWED;
+
+const MAN_PAGE: &'static str = /* @MANSTART{pwunconv} */ r#"
+NAME
+    pwunconv - move password hashes back into the passwd file
+
+SYNOPSIS
+    pwunconv [ -R | --root root_dir ]
+    pwunconv [ -h | --help ]
+
+DESCRIPTION
+    The pwunconv utility copies the password hashes kept in the shadow file
+    back into the passwd file and removes the shadow file. Password aging
+    information is lost.
+
+OPTIONS
+    -h, --help
+        Display this help and exit.
+
+    -R, --root root_dir
+        Convert the account database found under root_dir instead
+        of the one under /.
+
+AUTHOR
+    Written by Jose Narvaez.
+"#; /* @MANEND */
+
+fn main() {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut stderr = io::stderr();
+
+    let mut parser = ArgParser::new(1)
+        .add_flag(&["h", "help"])
+        .add_opt("R", "root");
+    parser.parse(env::args());
+
+    // Shows the help
+    if parser.found("help") {
+        stdout.write_all(MAN_PAGE.as_bytes()).try(&mut stderr);
+        stdout.flush().try(&mut stderr);
+        exit(0);
+    }
+
+    let db = match parser.get_opt("root") {
+        Some(root) => FileDb::new(root),
+        None => FileDb::default()
+    };
+
+    let lock = db.lock().unwrap_or_else(|err| {
+        eprintln!("pwunconv: cannot lock account database: {}", err);
+        exit(1);
+    });
+    let result = unconvert(&db);
+    drop(lock);
+
+    if let Err(msg) = result {
+        eprintln!("pwunconv: {}", msg);
+        exit(1);
+    }
+}
+
+fn unconvert(db: &FileDb) -> Result<(), String> {
+    if ! db.has_shadow() {
+        return Err(format!("{} does not exist", db.shadow_path().display()));
+    }
+
+    let users = db.users().map_err(|err| err.to_string())?;
+    let users = users.into_iter().map(|user| {
+        let user = db.with_shadow(user);
+        if user.hash == SHADOWED {
+            eprintln!("pwunconv: '{}' has no shadow entry, leaving it locked", user.user);
+        }
+        user
+    }).collect::<Vec<_>>();
+
+    db.save_users(&users)
+        .map_err(|err| format!("failed to write {}: {}", db.passwd_path().display(), err))?;
+
+    fs::remove_file(db.shadow_path())
+        .map_err(|err| format!("failed to remove {}: {}", db.shadow_path().display(), err))
+}
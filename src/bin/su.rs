@@ -2,7 +2,6 @@
 
 extern crate arg_parser;
 extern crate extra;
-extern crate termion;
 extern crate redox_users;
 extern crate userutils;
 
@@ -13,9 +12,9 @@ use std::str;
 
 use arg_parser::ArgParser;
 use extra::option::OptionalExt;
-use termion::input::TermRead;
-use redox_users::{get_uid, get_user_by_name};
-use userutils::spawn_shell;
+use redox_users::get_uid;
+use userutils::{AccountDb, AuthContext, AuthResult, AuthStack, EnvPolicy, FileDb, PasswordSource, User, exit_code,
+                spawn_shell};
 
 const MAN_PAGE: &'static str = /* @MANSTART{su} */ r#"
 NAME
@@ -40,8 +39,6 @@ AUTHOR
 "#; /* @MANEND */
 
 pub fn main() {
-    let stdin = io::stdin();
-    let mut stdin = stdin.lock();
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
     let mut stderr = io::stderr();
@@ -63,28 +60,56 @@ pub fn main() {
         parser.args[0].to_string()
     };
 
+    let db = FileDb::default();
     let uid = get_uid();
-    let user = get_user_by_name(&target_user).unwrap_or_else(|| {
+    let user = db.user_by_name(&target_user).map(|user| db.with_shadow(user)).unwrap_or_else(|| {
         eprintln!("su: user {} not found", target_user);
         exit(1);
     });
+    let groups = db.user_groups(&user);
 
-    if uid > 0 || user.hash != "" {
-        stdout.write_all(b"password: ").try(&mut stderr);
-        stdout.flush().try(&mut stderr);
-
-        if let Some(password) = stdin.read_passwd(&mut stdout).try(&mut stderr) {
+    let env = EnvPolicy::load().unwrap_or_else(|err| {
+        eprintln!("su: {}", err);
+        exit(1);
+    });
+    let stack = AuthStack::load("su").unwrap_or_else(|err| {
+        eprintln!("su: {}", err);
+        exit(1);
+    });
+    let ctx = AuthContext {
+        user: &user,
+        caller: uid as u32,
+        prompt: "password: ".to_string(),
+        source: PasswordSource::Terminal,
+        upgrade: Some(&db),
+    };
 
-            if user.verify_passwd(&password) {
-                spawn_shell(user);
-                exit(0);
-            } else {
-                stdout.write(b"su: authentication failed\n").try(&mut stderr);
-                stdout.flush().try(&mut stderr);
-                exit(1);
-            }
+    match stack.authenticate(&ctx) {
+        AuthResult::Success => (),
+        AuthResult::Denied => {
+            eprintln!("su: permission denied");
+            exit(1);
+        },
+        _ => {
+            eprintln!("su: authentication failed");
+            exit(1);
         }
     }
 
-    spawn_shell(user);
+    if stack.open_session(&ctx) != AuthResult::Success {
+        eprintln!("su: session refused for '{}'", user.user);
+        exit(1);
+    }
+
+    run_shell(&user, &groups, &env);
+}
+
+fn run_shell(user: &User, groups: &[u32], env: &EnvPolicy) -> ! {
+    match spawn_shell(user, groups, false, env) {
+        Ok(status) => exit(exit_code(status)),
+        Err(err) => {
+            eprintln!("su: failed to execute shell '{}': {}", user.shell, err);
+            exit(1);
+        }
+    }
 }
@@ -1,31 +1,78 @@
 #![deny(warnings)]
 
-extern crate arg_parser;
 extern crate syscall;
-extern crate termion;
 extern crate redox_users;
+extern crate termion;
+extern crate userutils;
 
 use std::env;
+use std::fmt;
+use std::fs;
 use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::process::CommandExt;
-use std::process::{Command, exit};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio, exit};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use arg_parser::ArgParser;
-use termion::input::TermRead;
-use redox_users::{get_uid, get_user_by_id, get_group_by_name};
+use redox_users::get_uid;
+use termion::raw::IntoRawMode;
+use userutils::{AccountDb, AuditLog, AuthContext, AuthResult, AuthStack, EnvPolicy, FileDb, Group, IoLog, PasswordSource,
+                Sudoers, Timestamps, User, current_tty, exit_code, pty, set_credentials, shell_command};
+use userutils::audit::{Event, Record};
+use userutils::edit;
+use userutils::environ::{is_unsafe, parse_assignment};
+use userutils::iolog::{Info, Recorder, Stream};
+use userutils::sudoers::{self, Decision, Identity, Request, Spec, Tags};
 
-const MAX_ATTEMPTS: u16 = 3;
 const MAN_PAGE: &'static str = /* @MANSTART{sudo} */ r#"
 NAME
     sudo - execute a command as another user
 
 SYNOPSIS
-    sudo command
+    sudo [ -n | -S | -A ] [ -b ] [ -k ] [ -E | --preserve-env=list ] [ -D dir ]
+         [ -u user ] [ -g group ] [ VAR=value... ] command [ args... ]
+    sudo [ -v | -k | -K ]
+    sudo -s [ -D dir ] [ -u user ] [ -g group ] [ command [ args... ] ]
+    sudo -i [ -u user ] [ -g group ] [ command [ args... ] ]
+    sudo -e [ -u user ] [ -g group ] file...
+    sudoedit [ -u user ] [ -g group ] file...
+    sudo -l [ -U user ] [ -u user ] [ -g group ] [ command [ args... ] ]
     sudo [ -h | --help ]
 
 DESCRIPTION
     The sudo utility allows a permitted user to execute a command as the
-    superuser or another user, as specified by the security policy.
+    superuser or another user, as specified by the security policy in
+    /etc/sudoers. Without that file, the members of the sudo group may
+    run any command.
+
+    Once authenticated, the user may run further commands from the same
+    terminal without entering a password for timestamp_timeout minutes
+    (5 by default).
+
+    Users who have no password set are refused, unless the policy sets the
+    allow_empty_password flag. Either way this is recorded in the audit log.
+
+    Commands given without a / are looked for in the secure_path of the
+    policy, /sbin:/bin:/usr/sbin:/usr/bin by default, never in the caller's
+    PATH. The policy is checked against the full path of the command, and
+    commands that anyone may modify, or that sit in a directory anyone may
+    write to, are refused.
+
+    Every command that is accepted, rejected or fails to authenticate, and
+    the exit status of the commands that ran, are appended to the audit log,
+    /var/log/sudo.log unless the policy sets logfile.
+
+    Commands tagged LOG_INPUT or LOG_OUTPUT by the policy run on a terminal
+    of their own, and what is typed or displayed is recorded under
+    /var/log/sudo-io, see sudoreplay(1). Such commands need a terminal.
+
+    Commands run in the current directory, or the one set by runcwd in the
+    policy, with the caller's umask combined with the policy's umask, 022
+    by default. Besides the environment described by /etc/login.defs they
+    get SUDO_USER, SUDO_UID and SUDO_GID, which describe the invoking user,
+    and SUDO_COMMAND, the command line that was run.
 
 OPTIONS
 
@@ -33,120 +80,1302 @@ OPTIONS
     --help
         Display this help and exit.
 
+    -u user
+    --user=user
+        Run the command as the given user, by name or as #uid, instead of
+        root.
+
+    -g group
+    --group=group
+        Run the command with the given primary group, by name or as #gid.
+        Without -u the command runs as the invoking user.
+
+    -s
+    --shell
+        Run the shell given by the SHELL environment variable, or the
+        target user's shell, in the current directory. A command given
+        after the options is run by the shell with -c.
+
+    -i
+    --login
+        Run the target user's shell as a login shell, which reads the
+        user's profile, from the target's home directory and with a reset
+        environment. A command given after the options is run by the shell
+        with -c.
+
+    -e
+    --edit
+        Edit the given files as the target user, which is what running sudo
+        as sudoedit does. Each file is copied to a temporary file owned by
+        the invoking user, which is opened with the editor named by
+        SUDO_EDITOR, VISUAL or EDITOR running with the invoking user's
+        rights. The files that changed are written back keeping their owner
+        and mode. Symbolic links and files in directories the invoking user
+        may write to are refused. The policy grants this as the sudoedit
+        command.
+
+    -v
+    --validate
+        Authenticate and refresh the timestamp of the terminal without
+        running a command.
+
+    -k
+    --reset-timestamp
+        Invalidate the timestamps of the user. With a command, ask for the
+        password and leave the timestamps alone.
+
+    -K
+    --remove-timestamp
+        Remove the timestamps of the user.
+
+    -l
+    --list
+        List the commands the user may run. With a command, print its full
+        path if the user may run it as the target given by -u and -g, and
+        exit with 1 otherwise.
+
+    -U user
+    --other-user=user
+        With -l, list the rights of the given user instead. Only root may
+        use this option.
+
+    -n
+    --non-interactive
+        Fail instead of asking for a password when one is needed.
+
+    -S
+    --stdin
+        Read the password from a line of the standard input instead of the
+        terminal, and write the prompt to the standard error.
+
+    -A
+    --askpass
+        Run the program named by SUDO_ASKPASS, as the invoking user and with
+        the prompt as argument, and use the first line it prints as the
+        password.
+
+    -E
+    --preserve-env
+        Keep the whole environment of the caller, except for the variables
+        that are never passed on. The policy has to allow it with the SETENV
+        tag.
+
+    --preserve-env=list
+        Keep the variables of the comma separated list. Each has to be
+        allowed by the policy, with the SETENV tag or the env_keep setting.
+
+    VAR=value
+        Arguments of this form before the command set the variable for the
+        command, with the same restrictions as --preserve-env=list.
+
+    -D dir
+    --chdir=dir
+        Run the command in the given directory. The policy has to allow it
+        by setting runcwd to *.
+
+    -b
+    --background
+        Run the command in the background, in a session of its own without a
+        controlling terminal and with its standard input, output and error
+        on /dev/null, and exit without waiting for it. Commands whose session
+        is recorded can not run in the background.
+
+    --
+        End the options, the next argument is the command.
+
 EXIT STATUS
     Upon successful execution of a command, the exit status from sudo will
-    be the exit status of the program that was executed. In case of error
+    be the exit status of the program that was executed, or 128 plus the
+    signal number when the program was killed by a signal. In case of error
     the exit status will be >0.
 
+SIGNALS
+    While the command runs, SIGHUP, SIGINT and SIGTERM sent to sudo are
+    forwarded to it and sudo keeps waiting for it to exit. The command runs
+    in a process group of its own, which is made the terminal's foreground
+    group while it runs, so that the signals typed on the terminal, ^C and ^Z
+    among them, reach the command directly and only once.
+
 AUTHOR
     Written by Jeremy Soller, Jose Narvaez.
 "#; /* @MANEND */
 
+/// Signals forwarded to the running command.
+const FORWARDED_SIGNALS: &'static [usize] = &[syscall::SIGHUP, syscall::SIGINT, syscall::SIGTERM];
+
+/// Pid of the running command, for `forward_signal`.
+static CHILD: AtomicUsize = AtomicUsize::new(0);
+/// Signal received before the command was started, forwarded once it is.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Options taking no value, with their short and long names.
+const FLAGS: &'static [(char, &'static str)] = &[
+    ('h', "help"),
+    ('v', "validate"),
+    ('k', "reset-timestamp"),
+    ('K', "remove-timestamp"),
+    ('l', "list"),
+    ('s', "shell"),
+    ('i', "login"),
+    ('e', "edit"),
+    ('n', "non-interactive"),
+    ('S', "stdin"),
+    ('A', "askpass"),
+    ('b', "background"),
+    ('E', "preserve-env"),
+];
+/// Options taking a value, with their short and long names.
+///
+/// `--preserve-env` is a flag, unless given a value with `=`.
+const VALUE_OPTIONS: &'static [(char, &'static str)] = &[
+    ('u', "user"),
+    ('g', "group"),
+    ('U', "other-user"),
+    ('E', "preserve-env"),
+    ('D', "chdir"),
+];
+
+/// Command line options.
+///
+/// They are parsed by hand, `ArgParser` would also pick up the options meant for
+/// the command.
+#[derive(Default)]
+struct Options {
+    help: bool,
+    validate: bool,
+    reset_timestamp: bool,
+    remove_timestamp: bool,
+    list: bool,
+    shell: bool,
+    login: bool,
+    edit: bool,
+    non_interactive: bool,
+    stdin: bool,
+    askpass: bool,
+    background: bool,
+    preserve_env: bool,
+    user: Option<String>,
+    group: Option<String>,
+    other_user: Option<String>,
+    chdir: Option<String>,
+    /// Variables given with --preserve-env=list.
+    preserve: Vec<String>,
+    /// Variables given as `VAR=value` before the command.
+    set_env: Vec<(String, String)>,
+    command: Vec<String>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options::default();
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            } else if arg.starts_with("--") {
+                let mut parts = arg[2..].splitn(2, '=');
+                let name = parts.next().unwrap_or("");
+                let inline = parts.next();
+
+                if FLAGS.iter().any(|&(_, long)| long == name) && inline.is_none() {
+                    options.flag(name);
+                } else if VALUE_OPTIONS.iter().any(|&(_, long)| long == name) {
+                    let value = inline.map(|value| value.to_string()).or_else(|| args.next())
+                        .ok_or_else(|| format!("option '--{}' requires an argument", name))?;
+                    options.set(name, value);
+                } else {
+                    return Err(format!("unknown option '{}'", arg));
+                }
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                for (i, c) in arg.char_indices().skip(1) {
+                    if let Some(&(_, long)) = FLAGS.iter().find(|&&(short, _)| short == c) {
+                        options.flag(long);
+                    } else if let Some(&(_, long)) = VALUE_OPTIONS.iter().find(|&&(short, _)| short == c) {
+                        let rest = &arg[i + c.len_utf8()..];
+                        let value = if rest.is_empty() { args.next() } else { Some(rest.to_string()) }
+                            .ok_or_else(|| format!("option '-{}' requires an argument", c))?;
+                        options.set(long, value);
+                        break;
+                    } else {
+                        return Err(format!("unknown option '-{}'", c));
+                    }
+                }
+            } else if let Some((name, value)) = parse_assignment(&arg) {
+                options.set_env.push((name.to_string(), value.to_string()));
+            } else {
+                options.command.push(arg);
+                break;
+            }
+        }
+
+        options.command.extend(args);
+        Ok(options)
+    }
+
+    fn flag(&mut self, name: &str) {
+        match name {
+            "help" => self.help = true,
+            "validate" => self.validate = true,
+            "reset-timestamp" => self.reset_timestamp = true,
+            "remove-timestamp" => self.remove_timestamp = true,
+            "list" => self.list = true,
+            "shell" => self.shell = true,
+            "login" => self.login = true,
+            "edit" => self.edit = true,
+            "non-interactive" => self.non_interactive = true,
+            "stdin" => self.stdin = true,
+            "askpass" => self.askpass = true,
+            "background" => self.background = true,
+            "preserve-env" => self.preserve_env = true,
+            _ => unreachable!("flag {} is not handled", name)
+        }
+    }
+
+    fn set(&mut self, name: &str, value: String) {
+        match name {
+            "user" => self.user = Some(value),
+            "group" => self.group = Some(value),
+            "other-user" => self.other_user = Some(value),
+            "chdir" => self.chdir = Some(value),
+            "preserve-env" => {
+                self.preserve.extend(value.split(',').filter(|name| ! name.is_empty()).map(|name| name.to_string()));
+            },
+            _ => unreachable!("option {} is not handled", name)
+        }
+    }
+}
+
 pub fn main() {
-    let stdin = io::stdin();
-    let mut stdin = stdin.lock();
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
 
-    let mut parser = ArgParser::new(1)
-        .add_flag(&["h", "help"]);
-    parser.parse(env::args());
+    let mut options = Options::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("sudo: {}", err);
+        exit(1);
+    });
+
+    // Run through a link named sudoedit, sudo acts as with -e
+    let arg0 = env::args().next().unwrap_or_default();
+    if Path::new(&arg0).file_name().map_or(false, |name| name == "sudoedit") {
+        options.edit = true;
+    }
 
     // Shows the help
-    if parser.found("help") {
+    if options.help {
         let _ = stdout.write_all(MAN_PAGE.as_bytes());
         let _ = stdout.flush();
         exit(0);
     }
 
-    let mut args = env::args().skip(1);
-    let cmd = args.next().unwrap_or_else(|| {
+    if ! options.command.is_empty() && (options.validate || options.remove_timestamp) {
+        eprintln!("sudo: -v and -K cannot be used with a command");
+        exit(1);
+    }
+    if [options.shell, options.login, options.edit].iter().filter(|&&mode| mode).count() > 1 {
+        eprintln!("sudo: only one of -s, -i and -e may be used");
+        exit(1);
+    }
+    if options.edit && options.command.is_empty() {
+        eprintln!("sudoedit: no file provided");
+        exit(1);
+    }
+    if [options.non_interactive, options.stdin, options.askpass].iter().filter(|&&mode| mode).count() > 1 {
+        eprintln!("sudo: only one of -n, -S and -A may be used");
+        exit(1);
+    }
+    if options.background && (options.command.is_empty() || options.edit || options.list || options.validate) {
+        eprintln!("sudo: -b needs a command and cannot be used with -e, -l or -v");
+        exit(1);
+    }
+    let passes_env = options.preserve_env || ! options.preserve.is_empty() || ! options.set_env.is_empty();
+    if passes_env && (options.login || options.edit || options.list || options.validate) {
+        eprintln!("sudo: -E, --preserve-env and VAR=value cannot be used with -i, -e, -l or -v");
+        exit(1);
+    }
+    if options.chdir.is_some() && (options.login || options.edit || options.list || options.validate) {
+        eprintln!("sudo: -D cannot be used with -i, -e, -l or -v");
+        exit(1);
+    }
+    if options.other_user.is_some() && ! options.list {
+        eprintln!("sudo: -U can only be used with -l");
+        exit(1);
+    }
+    let shell = options.shell || options.login;
+    if options.command.is_empty() && ! (shell || options.validate || options.list || options.reset_timestamp || options.remove_timestamp) {
         eprintln!("sudo: no command provided");
         exit(1);
-    });
+    }
 
+    let db = FileDb::default();
     let uid = get_uid();
-    let user = get_user_by_id(uid).unwrap_or_else(|| {
+    let user = db.user_by_id(uid as u32).map(|user| db.with_shadow(user)).unwrap_or_else(|| {
         eprintln!("sudo: user not found");
         exit(1);
     });
 
-    if uid != 0 {
-        let sudo_group = get_group_by_name("sudo").unwrap_or_else(|| {
-            eprintln!("sudo: sudo group not found");
+    let policy = Sudoers::load().unwrap_or_else(|err| {
+        eprintln!("sudo: {}", err);
+        exit(1);
+    });
+    let timestamps = Timestamps::new(policy.timestamp_dir());
+    let mut audit = Audit::new(policy.audit_log(), &user.user);
+
+    if options.remove_timestamp {
+        if let Err(err) = timestamps.remove(&user.user) {
+            eprintln!("sudo: failed to remove timestamp of '{}': {}", user.user, err);
             exit(1);
-        });
+        }
+        exit(0);
+    }
 
-        if sudo_group.users.iter().any(|name| name == &user.user) {
-            if ! user.hash.is_empty() {
-                let max_attempts = MAX_ATTEMPTS;
-                let mut attempts = 0;
-
-                loop {
-                    print!("[sudo] password for {}: ", user.user);
-                    let _ = stdout.flush();
-
-                    match stdin.read_passwd(&mut stdout).unwrap() {
-                        Some(password) => {
-                            write!(stdout, "\n").unwrap();
-                            let _ = stdout.flush();
-
-                            if user.verify_passwd(&password) {
-                                break;
-                            } else {
-                                attempts += 1;
-                                eprintln!("sudo: incorrect password ({}/{})", attempts, max_attempts);
-                                if attempts >= max_attempts {
-                                    exit(1);
-                                }
-                            }
-                        },
-                        None => {
-                            write!(stdout, "\n").unwrap();
-                            exit(1);
-                        }
-                    }
-                }
-            } else {
-                // FIXME: We should not be doing this as provides access to any
-                // user w/o auth to run stuff as root. We should be doing something like:
-                // eprintln!("sudo: '{}' is in sudo group but does not have a password set", user.user);
-                // exit(1);
-                run_command_as_root(&cmd, &args.collect::<Vec<String>>());
-                exit(0);
-            }
-        } else {
-            eprintln!("sudo: '{}' not in sudo group", user.user);
+    // -k with a command or shell asks for the password and leaves the records alone
+    let runs = shell || ! options.command.is_empty();
+    if options.reset_timestamp && ! runs {
+        if let Err(err) = timestamps.invalidate(&user.user) {
+            eprintln!("sudo: failed to invalidate timestamp of '{}': {}", user.user, err);
+            exit(1);
+        }
+        if ! (options.validate || options.list) {
+            exit(0);
+        }
+    }
+
+    let source = password_source(&options, &user);
+
+    let user_groups = db.user_groups(&user);
+    let user_group_names = group_names(&db, &user_groups);
+    let host = sudoers::hostname();
+    let identity = Identity { name: &user.user, groups: &user_group_names };
+
+    let cache = if options.reset_timestamp && runs {
+        None
+    } else {
+        Some((&timestamps, policy.timestamp_timeout()))
+    };
+
+    if options.validate {
+        if policy.rules_for(&identity, &host).is_empty() {
+            eprintln!("sudo: '{}' may not run sudo on {}", user.user, host);
             exit(1);
         }
+
+        authenticate(&user, uid as u32, true, policy.allow_empty_password(), cache, &source, &audit);
+        exit(0);
+    }
+
+    if options.list {
+        list(&db, &policy, &user, uid as u32, &host, &options, cache, &source, &audit);
+    }
+
+    // Unknown targets and commands that can not run are attempts too
+    audit.set_requested(&options);
+    let program = if options.edit { "sudoedit" } else { "sudo" };
+    let target = Target::resolve(&db, &user, &options).unwrap_or_else(|msg| {
+        audit.log(Event::Reject);
+        eprintln!("{}: {}", program, msg);
+        exit(1);
+    });
+    let (path, args) = command_line(&target, &options, policy.secure_path()).unwrap_or_else(|msg| {
+        audit.log(Event::Reject);
+        eprintln!("{}: {}", program, msg);
+        exit(1);
+    });
+    let command = path.to_string_lossy();
+    audit.set_command(&target, &command, &args);
+
+    let tags = match policy.check(&target.request(identity, &host, &command, &args)) {
+        Decision::Allow(tags) => tags,
+        Decision::Deny => {
+            audit.log(Event::Reject);
+            eprintln!("sudo: '{}' is not allowed to run '{}' as '{}'", user.user, command, target);
+            exit(1);
+        }
+    };
+    if let Err(msg) = check_environment(&options, &policy, tags) {
+        audit.log(Event::Reject);
+        eprintln!("sudo: {} for '{}'", msg, command);
+        exit(1);
+    }
+    let run_dir = run_dir(&options, &policy).unwrap_or_else(|msg| {
+        audit.log(Event::Reject);
+        eprintln!("sudo: {} for '{}'", msg, command);
+        exit(1);
+    });
+
+    let stack = authenticate(&user, uid as u32, tags.passwd, policy.allow_empty_password(), cache,
+                             &source, &audit);
+    if stack.open_session(&auth_context(&user, uid as u32, &source)) != AuthResult::Success {
+        eprintln!("sudo: session refused for '{}'", user.user);
+        exit(1);
+    }
+
+    // Recorded commands run on a pseudo terminal relayed to the caller's
+    let recording = if (tags.log_input || tags.log_output) && ! options.edit {
+        if options.background {
+            eprintln!("sudo: '{}' is recorded and cannot run in the background", command);
+            exit(1);
+        }
+        Some(start_recording(&policy.io_log(), &mut audit))
+    } else {
+        None
+    };
+
+    audit.log(Event::Accept);
+
+    if options.edit {
+        edit(&user, &user_groups, &target, &args, &audit);
+    }
+
+    let mut env = EnvPolicy::load().unwrap_or_else(|err| {
+        eprintln!("sudo: {}", err);
+        exit(1);
+    });
+    env.keep.extend(policy.env_keep().into_iter().map(|name| name.to_string()));
+    // A login shell starts from a clean environment whatever the policy says
+    if options.login {
+        env.reset = true;
+    }
+    if options.preserve_env {
+        env.reset = false;
+    }
+    env.keep.extend(options.preserve.iter().cloned());
+    env.set.extend(options.set_env.iter().cloned());
+    // Set last, so that the caller can not override them
+    env.set.push(("SUDO_USER".to_string(), user.user.clone()));
+    env.set.push(("SUDO_UID".to_string(), user.uid.to_string()));
+    env.set.push(("SUDO_GID".to_string(), user.gid.to_string()));
+    env.set.push(("SUDO_COMMAND".to_string(), audit.command.join(" ")));
+
+    // The command gets the caller's umask with the policy's bits added
+    let mask = policy.umask() as usize;
+    if let Ok(old) = syscall::umask(mask) {
+        let _ = syscall::umask(old | mask);
+    }
+
+    let mut command = if options.shell || options.login {
+        let mut shell_user = target.user.clone();
+        shell_user.shell = command.into_owned();
+        shell_user.gid = target.gid;
+
+        let mut command = shell_command(&shell_user, &target.groups, options.login, &env);
+        if ! options.login {
+            if let Ok(dir) = env::current_dir() {
+                command.current_dir(dir);
+            }
+        }
+        command
+    } else {
+        let mut command = Command::new(&path);
+        set_credentials(&mut command, target.user.uid, target.gid, &target.groups);
+        env.apply(&mut command, &target.user, &target.groups);
+        command
+    };
+    command.args(&args);
+    if let Some(dir) = run_dir {
+        if ! options.login {
+            command.current_dir(dir);
+        }
+    }
+
+    match recording {
+        Some(recording) => run_recorded(command, &path, &audit, recording, tags),
+        None if options.background => run_background(command, &path),
+        None => run_command(command, &path, &audit)
+    }
+}
+
+/// Who a command runs as.
+struct Target {
+    user: User,
+    /// The group given with -g.
+    group: Option<Group>,
+    /// Primary group.
+    gid: u32,
+    /// Supplementary groups, primary one first.
+    groups: Vec<u32>,
+    group_names: Vec<String>,
+}
+
+impl Target {
+    /// Finds the target selected by -u and -g, telling which one does not exist
+    /// when either does not.
+    fn resolve(db: &FileDb, user: &User, options: &Options) -> Result<Target, String> {
+        // With only -g the command keeps running as the invoking user
+        let target = match options.user {
+            Some(ref name) => lookup_user(db, name).ok_or_else(|| format!("unknown user '{}'", name))?,
+            None if options.group.is_some() => user.clone(),
+            None => db.user_by_id(0).ok_or_else(|| "root user not found".to_string())?
+        };
+        let group = match options.group {
+            Some(ref name) => Some(lookup_group(db, name).ok_or_else(|| format!("unknown group '{}'", name))?),
+            None => None
+        };
+
+        let mut groups = db.user_groups(&target);
+        let gid = match group {
+            Some(ref group) => {
+                groups.retain(|&gid| gid != group.gid);
+                groups.insert(0, group.gid);
+                group.gid
+            },
+            None => target.gid
+        };
+
+        Ok(Target {
+            group_names: group_names(db, &groups),
+            user: target,
+            group: group,
+            gid: gid,
+            groups: groups,
+        })
     }
 
-    run_command_as_root(&cmd, &args.collect::<Vec<String>>());
-    exit(0);
+    /// The policy request for `user` running `command` as this target.
+    fn request<'a>(&'a self, user: Identity<'a>, host: &'a str, command: &'a str, args: &'a [String]) -> Request<'a> {
+        Request {
+            user: user,
+            host: host,
+            runas_user: Identity { name: &self.user.user, groups: &self.group_names },
+            runas_group: self.group.as_ref().map(|group| group.group.as_str()),
+            command: command,
+            args: args,
+        }
+    }
 }
 
-fn run_command_as_root(cmd: &str, args: &Vec<String>) {
-    let mut command = Command::new(&cmd);
-    for arg in args {
-        command.arg(&arg);
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.group {
+            Some(ref group) => write!(f, "{}:{}", self.user.user, group.group),
+            None => write!(f, "{}", self.user.user)
+        }
     }
+}
+
+/// Writes the records of one invocation to the audit log.
+struct Audit {
+    log: AuditLog,
+    user: String,
+    /// The target and command line, once known.
+    target: String,
+    command: Vec<String>,
+    /// The id of the recorded session, if any.
+    session: Option<String>,
+}
 
-    command.uid(0);
-    command.gid(0);
-    command.env("USER", "root");
-    command.env("UID", "0");
-    command.env("GROUPS", "0");
+impl Audit {
+    fn new(log: AuditLog, user: &str) -> Audit {
+        Audit {
+            log: log,
+            user: user.to_string(),
+            target: String::new(),
+            command: Vec::new(),
+            session: None,
+        }
+    }
 
-    match command.spawn() {
-        Ok(mut child) => match child.wait() {
-            Ok(status) => exit(status.code().unwrap_or(0)),
+    /// Uses the target and command line as given, until they are resolved.
+    fn set_requested(&mut self, options: &Options) {
+        self.target = match (options.user.as_ref(), options.group.as_ref()) {
+            (Some(user), Some(group)) => format!("{}:{}", user, group),
+            (Some(user), None) => user.clone(),
+            (None, Some(group)) => format!("{}:{}", self.user, group),
+            (None, None) => "root".to_string()
+        };
+        self.command = options.command.clone();
+        if options.edit {
+            self.command.insert(0, sudoers::SUDOEDIT.to_string());
+        }
+    }
+
+    fn set_command(&mut self, target: &Target, command: &str, args: &[String]) {
+        self.target = target.to_string();
+        self.command = Some(command.to_string()).into_iter().chain(args.iter().cloned()).collect();
+    }
+
+    /// The record of `event` for this invocation.
+    fn record(&self, event: Event) -> Record {
+        let mut record = Record::new(&self.user, event);
+        record.target = self.target.clone();
+        record.command = self.command.clone();
+        record.session = self.session.clone();
+        record
+    }
+
+    /// Logs `event`, only warning when the log can not be written so that a full
+    /// disk does not lock everyone out.
+    fn log(&self, event: Event) {
+        let record = self.record(event);
+        if let Err(err) = self.log.write(&record) {
+            eprintln!("sudo: failed to write {}: {}", self.log.path().display(), err);
+        }
+    }
+}
+
+/// Checks that the policy lets the variables requested with -E, --preserve-env and
+/// `VAR=value` through, naming the ones it refuses.
+///
+/// `SETENV` allows any variable and `env_keep` the ones it lists, but the
+/// variables that are never passed on are always refused.
+fn check_environment(options: &Options, policy: &Sudoers, tags: Tags) -> Result<(), String> {
+    if options.preserve_env && ! tags.setenv {
+        return Err("the policy does not allow preserving the environment".to_string());
+    }
+
+    let env_keep = policy.env_keep();
+    let denied = options.preserve.iter()
+        .chain(options.set_env.iter().map(|&(ref name, _)| name))
+        .filter(|name| is_unsafe(name) || ! (tags.setenv || env_keep.contains(&name.as_str())))
+        .map(|name| name.as_str())
+        .collect::<Vec<&str>>();
+
+    if denied.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("the policy does not allow setting {}", denied.join(", ")))
+    }
+}
+
+/// The directory the command runs in, `None` for the caller's.
+///
+/// -D is only honoured when `runcwd` is `*`, another `runcwd` is used as is.
+fn run_dir(options: &Options, policy: &Sudoers) -> Result<Option<PathBuf>, String> {
+    match (options.chdir.as_ref(), policy.run_cwd()) {
+        (Some(dir), Some("*")) => Ok(Some(PathBuf::from(dir))),
+        (Some(_), _) => Err("the policy does not allow choosing the directory".to_string()),
+        (None, Some("*")) | (None, None) => Ok(None),
+        (None, Some(dir)) => Ok(Some(PathBuf::from(dir)))
+    }
+}
+
+/// Handles -l, listing what the policy grants or checking a single command.
+///
+/// Exits with 0 when something is allowed, 1 otherwise.
+fn list(db: &FileDb, policy: &Sudoers, user: &User, caller: u32, host: &str, options: &Options,
+        cache: Option<(&Timestamps, Duration)>, source: &PasswordSource, audit: &Audit) -> ! {
+    let listed = match options.other_user {
+        Some(ref name) if caller != 0 => {
+            eprintln!("sudo: only root may list the rights of '{}'", name);
+            exit(1);
+        },
+        Some(ref name) => lookup_user(db, name).unwrap_or_else(|| {
+            eprintln!("sudo: unknown user '{}'", name);
+            exit(1);
+        }),
+        None => user.clone()
+    };
+    let listed_group_names = group_names(db, &db.user_groups(&listed));
+    let identity = Identity { name: &listed.user, groups: &listed_group_names };
+
+    let specs = policy.rules_for(&identity, host).into_iter()
+        .flat_map(|rule| rule.specs.iter())
+        .collect::<Vec<&Spec>>();
+
+    if specs.is_empty() {
+        println!("User {} is not allowed to run sudo on {}.", listed.user, host);
+        exit(1);
+    }
+
+    // Like for commands, no password is needed if the policy waives it somewhere
+    let passwd = specs.iter().all(|spec| spec.tags.passwd);
+    authenticate(user, caller, passwd, policy.allow_empty_password(), cache, source, audit);
+
+    if options.command.is_empty() {
+        println!("User {} may run the following commands on {}:", listed.user, host);
+        for spec in specs {
+            for cmnd in policy.expand_cmnd(&spec.cmnd) {
+                println!("    {} {}{}", spec.runas, spec.tags, cmnd);
+            }
+        }
+        exit(0);
+    }
+
+    let target = Target::resolve(db, &listed, options).unwrap_or_else(|msg| {
+        eprintln!("sudo: {}", msg);
+        exit(1);
+    });
+    let (path, args) = command_line(&target, options, policy.secure_path()).unwrap_or_else(|msg| {
+        eprintln!("sudo: {}", msg);
+        exit(1);
+    });
+    let command = path.to_string_lossy();
+
+    match policy.check(&target.request(identity, host, &command, &args)) {
+        Decision::Allow(_) => {
+            let mut line = command.into_owned();
+            for arg in args.iter() {
+                line.push(' ');
+                line.push_str(arg);
+            }
+            println!("{}", line);
+            exit(0);
+        },
+        Decision::Deny => exit(1)
+    }
+}
+
+/// Handles -e: lets the invoking user edit copies of `files` with its own rights,
+/// then writes the changed ones back as `target`.
+fn edit(user: &User, user_groups: &[u32], target: &Target, files: &[String], audit: &Audit) -> ! {
+    let files = files.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+
+    let mut checked = Vec::new();
+    for file in files.iter() {
+        match edit::check_editable(file, user.uid, user_groups, &target.user, &target.groups) {
+            Ok(metadata) => checked.push(metadata),
             Err(err) => {
-                eprintln!("sudo: failed to wait for {}: {}", cmd, err);
+                eprintln!("sudoedit: {}", err);
                 exit(1);
             }
+        }
+    }
+
+    let mut copies = Vec::new();
+    for (file, metadata) in files.iter().zip(checked.iter()) {
+        let copy = edit::read_checked(file, metadata.as_ref())
+            .and_then(|data| Ok((edit::temp_copy(file, &data, user.uid, user.gid)?, data)));
+        match copy {
+            Ok(copy) => copies.push(copy),
+            Err(err) => {
+                eprintln!("sudoedit: failed to copy {}: {}", file.display(), err);
+                remove_copies(&copies);
+                exit(1);
+            }
+        }
+    }
+
+    let editor = edit::editor();
+    let mut command = Command::new(&editor[0]);
+    command.args(&editor[1..]);
+    command.args(copies.iter().map(|&(ref copy, _)| copy));
+    set_credentials(&mut command, user.uid, user.gid, user_groups);
+
+    let status = command.spawn().and_then(|mut child| child.wait());
+    if let Ok(status) = status {
+        audit.log(Event::Exit(exit_code(status)));
+    }
+    match status {
+        Ok(ref status) if status.success() => (),
+        Ok(status) => {
+            eprintln!("sudoedit: {} exited with {}, files left unchanged", editor[0], status);
+            remove_copies(&copies);
+            exit(1);
+        },
+        Err(err) => {
+            eprintln!("sudoedit: failed to execute {}: {}", editor[0], err);
+            remove_copies(&copies);
+            exit(1);
+        }
+    }
+
+    let mut failed = false;
+    for (file, &(ref copy, ref original)) in files.iter().zip(copies.iter()) {
+        let data = match edit::read_copy(copy, user.uid) {
+            Ok(data) => data,
+            Err(err) => {
+                eprintln!("sudoedit: failed to read {}: {}", copy.display(), err);
+                failed = true;
+                continue;
+            }
+        };
+
+        match edit::write_changes(file, &data, original, edit::NEW_FILE_MODE, target.user.uid, target.gid) {
+            Ok(true) => (),
+            Ok(false) => eprintln!("sudoedit: {} unchanged", file.display()),
+            Err(err) => {
+                eprintln!("sudoedit: failed to write {}: {}, contents kept in {}", file.display(), err, copy.display());
+                failed = true;
+                continue;
+            }
+        }
+
+        let _ = fs::remove_file(copy);
+    }
+
+    exit(if failed { 1 } else { 0 });
+}
+
+fn remove_copies(copies: &[(PathBuf, Vec<u8>)]) {
+    for &(ref copy, _) in copies.iter() {
+        let _ = fs::remove_file(copy);
+    }
+}
+
+/// Where the password is read from as chosen by -S and -A, nowhere when -n forbids
+/// asking for one. Exits when -A is given without `SUDO_ASKPASS`.
+fn password_source(options: &Options, user: &User) -> PasswordSource {
+    if options.non_interactive {
+        return PasswordSource::NonInteractive;
+    }
+    if options.stdin {
+        return PasswordSource::Stdin;
+    }
+    if ! options.askpass {
+        return PasswordSource::Terminal;
+    }
+
+    match env::var("SUDO_ASKPASS") {
+        Ok(ref program) if ! program.is_empty() => PasswordSource::Askpass {
+            program: program.clone(),
+            uid: user.uid,
+            gid: user.gid,
+        },
+        _ => {
+            eprintln!("sudo: no askpass program specified, try setting SUDO_ASKPASS");
+            exit(1);
+        }
+    }
+}
+
+fn auth_context<'a>(user: &'a User, caller: u32, source: &PasswordSource) -> AuthContext<'a> {
+    AuthContext {
+        user: user,
+        caller: caller,
+        prompt: format!("[sudo] password for {}: ", user.user),
+        source: source.clone(),
+        upgrade: None,
+    }
+}
+
+/// Runs the `sudo` stack for `user`, exiting on failure.
+///
+/// Only the `account` phase runs when `passwd` is false, or when `cache` holds a
+/// record of a recent authentication on this terminal. Otherwise the password is
+/// read from `source`, with -n failing once a module needs one. Successful password
+/// checks are recorded in `cache`, failures in `audit`.
+///
+/// A user without a password, other than root, is refused unless `empty_ok`, the
+/// policy's `allow_empty_password`, in which case only the `account` phase runs.
+/// Either way the decision goes to `audit`.
+fn authenticate(user: &User, caller: u32, passwd: bool, empty_ok: bool, cache: Option<(&Timestamps, Duration)>,
+                source: &PasswordSource, audit: &Audit) -> AuthStack {
+    let stack = AuthStack::load("sudo").unwrap_or_else(|err| {
+        eprintln!("sudo: {}", err);
+        exit(1);
+    });
+    let ctx = auth_context(user, caller, source);
+
+    // Timestamps are kept per terminal. Without one, as when -S reads the password
+    // from a pipe or a script uses -A, every run authenticates: a record keyed on
+    // anything else would be shared by all the user's processes
+    let tty = current_tty();
+    let cached = match (cache, tty.as_ref()) {
+        (Some((timestamps, timeout)), Some(tty)) => timestamps.is_valid(&user.user, tty, timeout),
+        _ => false
+    };
+
+    // The stack would let them through with `unix nullok`, which is the built-in one
+    let empty = passwd && ! cached && caller != 0 && user.hash.is_empty();
+    if empty {
+        audit.log(Event::EmptyPassword(empty_ok));
+        if ! empty_ok {
+            eprintln!("sudo: '{}' does not have a password set, which the policy requires", user.user);
+            exit(1);
+        }
+    }
+
+    let result = if passwd && ! cached && ! empty {
+        stack.authenticate(&ctx)
+    } else {
+        stack.check_account(&ctx)
+    };
+
+    match result {
+        AuthResult::Success => (),
+        // With -n, a module wanted to prompt
+        AuthResult::Aborted if *source == PasswordSource::NonInteractive => {
+            audit.log(Event::AuthFailure);
+            eprintln!("sudo: a password is required");
+            exit(1);
+        },
+        AuthResult::Denied => {
+            audit.log(Event::AuthFailure);
+            eprintln!("sudo: permission denied");
+            exit(1);
+        },
+        _ => {
+            audit.log(Event::AuthFailure);
+            eprintln!("sudo: authentication failed");
+            exit(1);
+        }
+    }
+
+    // Records are per terminal, without one there is nothing to remember. Nothing
+    // was checked for users without a password, so each run is audited again
+    if let (true, Some((timestamps, _)), Some(tty)) = (passwd && ! empty, cache, tty) {
+        if let Err(err) = timestamps.update(&user.user, &tty) {
+            eprintln!("sudo: failed to record timestamp of '{}': {}", user.user, err);
+        }
+    }
+
+    stack
+}
+
+/// The command to run and its arguments, an error when it can not be found or is
+/// not safe to run.
+///
+/// With -e that is `sudoedit` and the absolute paths of the files, as the policy
+/// names it. With -s or -i that is the shell, running the command line if any with `-c`.
+/// -s uses the caller's `SHELL` when set, -i the target's shell.
+fn command_line(target: &Target, options: &Options, secure_path: &str) -> Result<(PathBuf, Vec<String>), String> {
+    if options.edit {
+        let dir = env::current_dir().map_err(|err| format!("failed to get current directory: {}", err))?;
+        let mut files = Vec::new();
+        for file in options.command.iter() {
+            files.push(edit_path(&dir, file)?.to_string_lossy().into_owned());
+        }
+        return Ok((PathBuf::from(sudoers::SUDOEDIT), files));
+    }
+
+    if ! (options.shell || options.login) {
+        return resolve_command_line(&options.command, secure_path);
+    }
+
+    let shell = match env::var("SHELL") {
+        Ok(ref shell) if options.shell && ! shell.is_empty() => shell.clone(),
+        _ => target.user.shell.clone()
+    };
+    let (path, _) = resolve_command_line(&[shell], secure_path)?;
+
+    let args = if options.command.is_empty() {
+        Vec::new()
+    } else {
+        let line = options.command.iter()
+            .map(|arg| escape(arg))
+            .collect::<Vec<String>>()
+            .join(" ");
+        vec!["-c".to_string(), line]
+    };
+
+    Ok((path, args))
+}
+
+/// The absolute path of a file to edit, as the policy sees it.
+///
+/// `..` is refused and the directory of the file is resolved, so that a path
+/// can not appear to be somewhere else than the file it names.
+fn edit_path(dir: &Path, file: &str) -> Result<PathBuf, String> {
+    let path = dir.join(file);
+    if path.components().any(|component| component == Component::ParentDir) {
+        return Err(format!("{}: '..' is not allowed in paths", file));
+    }
+
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => return Err(format!("{}: not a file", file))
+    };
+    let parent = fs::canonicalize(parent).map_err(|err| format!("{}: {}", parent.display(), err))?;
+    Ok(parent.join(name))
+}
+
+/// Escapes the characters of `arg` the shell could interpret.
+fn escape(arg: &str) -> String {
+    let mut escaped = String::new();
+    for c in arg.chars() {
+        if ! (c.is_alphanumeric() || "_-+./=:@%,".contains(c)) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Splits the command line into the resolved command and its arguments, an error
+/// when the command can not be found or is not safe to run.
+fn resolve_command_line(command: &[String], secure_path: &str) -> Result<(PathBuf, Vec<String>), String> {
+    let cmd = &command[0];
+    let path = resolve_command(cmd, secure_path).ok_or_else(|| format!("{}: command not found", cmd))?;
+    check_command(&path)?;
+
+    Ok((path, command[1..].to_vec()))
+}
+
+/// Finds `cmd` in `secure_path`, a command containing a `/` is looked for from the
+/// current directory.
+///
+/// The directory of the result is canonical, so that the policy is checked
+/// against the path that actually runs. The file name is kept as is, a rule for a
+/// symbolic link allows it whatever it points to.
+fn resolve_command(cmd: &str, secure_path: &str) -> Option<PathBuf> {
+    let path = if cmd.contains('/') {
+        Some(env::current_dir().ok()?.join(cmd)).filter(|path| path.is_file())
+    } else {
+        secure_path.split(':')
+            .filter(|dir| Path::new(dir).is_absolute())
+            .map(|dir| Path::new(dir).join(cmd))
+            .find(|path| path.is_file())
+    }?;
+
+    let dir = fs::canonicalize(path.parent()?).ok()?;
+    Some(dir.join(path.file_name()?))
+}
+
+/// Refuses commands anyone could have replaced, because they or their directory
+/// are writable by everyone.
+fn check_command(path: &Path) -> Result<(), String> {
+    let world_writable = |path: &Path| fs::metadata(path).map(|metadata| metadata.mode() & 0o002 != 0);
+
+    if let Some(dir) = path.parent() {
+        match world_writable(dir) {
+            Ok(false) => (),
+            Ok(true) => return Err(format!("{}: refusing to run a command from {}, which anyone may write to",
+                                           path.display(), dir.display())),
+            Err(err) => return Err(format!("{}: {}", dir.display(), err))
+        }
+    }
+
+    match world_writable(path) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(format!("{}: refusing to run a command anyone may write to", path.display())),
+        Err(err) => Err(format!("{}: {}", path.display(), err))
+    }
+}
+
+/// Finds a user by name, or by uid when written `#uid`.
+fn lookup_user(db: &FileDb, name: &str) -> Option<User> {
+    if name.starts_with('#') {
+        db.user_by_id(name[1..].parse().ok()?)
+    } else {
+        db.user_by_name(name)
+    }
+}
+
+/// Finds a group by name, or by gid when written `#gid`.
+fn lookup_group(db: &FileDb, name: &str) -> Option<Group> {
+    if name.starts_with('#') {
+        db.group_by_id(name[1..].parse().ok()?)
+    } else {
+        db.group_by_name(name)
+    }
+}
+
+/// Names of the groups with the given ids, unknown ones are skipped.
+fn group_names(db: &FileDb, gids: &[u32]) -> Vec<String> {
+    gids.iter()
+        .filter_map(|&gid| db.group_by_id(gid))
+        .map(|group| group.group)
+        .collect()
+}
+
+/// Runs `command` and exits with its exit code, or 128 plus the signal that
+/// killed it.
+fn run_command(mut command: Command, path: &Path, audit: &Audit) -> ! {
+    let cmd = path.display();
+    forward_signals();
+    own_process_group(&mut command);
+    let mut child = command.spawn().unwrap_or_else(|err| {
+        eprintln!("sudo: failed to execute {}: {}", cmd, err);
+        exit(1);
+    });
+    supervise(&child);
+
+    let status = wait(&mut child);
+    restore_foreground();
+    match status {
+        Ok(status) => {
+            let code = exit_code(status);
+            audit.log(Event::Exit(code));
+            exit(code);
+        },
+        Err(err) => {
+            eprintln!("sudo: failed to wait for {}: {}", cmd, err);
+            exit(1);
+        }
+    }
+}
+
+/// Starts `command` in a session of its own, detached from the terminal, and exits
+/// without waiting for it.
+fn run_background(mut command: Command, path: &Path) -> ! {
+    command.stdin(Stdio::null());
+    command.stdout(Stdio::null());
+    command.stderr(Stdio::null());
+    // A session of its own leaves the terminal behind, along with its hangups
+    unsafe {
+        command.pre_exec(|| {
+            if setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    match command.spawn() {
+        Ok(_) => exit(0),
+        Err(err) => {
+            eprintln!("sudo: failed to execute {}: {}", path.display(), err);
+            exit(1);
+        }
+    }
+}
+
+/// Waits for `child`, going on when a forwarded signal interrupts the wait.
+fn wait(child: &mut Child) -> io::Result<ExitStatus> {
+    loop {
+        match child.wait() {
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            result => return result
+        }
+    }
+}
+
+/// Puts the process `command` starts in a process group of its own.
+///
+/// The group is handed the terminal on stdin if there is one, so that the command
+/// can use it and the signals typed on it reach the command directly rather than
+/// through sudo. `restore_foreground` takes it back.
+fn own_process_group(command: &mut Command) {
+    let foreground = termion::is_tty(&io::stdin());
+    unsafe {
+        command.pre_exec(move || {
+            syscall::setpgid(0, 0).map_err(from_syscall)?;
+            if foreground {
+                set_foreground(syscall::getpid().map_err(from_syscall)?)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Makes sudo's process group the foreground group of the terminal on stdin
+/// again, once the command is done with it.
+fn restore_foreground() {
+    if termion::is_tty(&io::stdin()) {
+        let _ = syscall::getpgid(0).map_err(from_syscall).and_then(set_foreground);
+    }
+}
+
+/// Makes `pgid` the foreground process group of the terminal on stdin.
+fn set_foreground(pgid: usize) -> io::Result<()> {
+    let fd = syscall::dup(0, b"pgrp").map_err(from_syscall)?;
+    let result = syscall::write(fd, &pgid.to_ne_bytes()).map(|_| ()).map_err(from_syscall);
+    let _ = syscall::close(fd);
+    result
+}
+
+fn from_syscall(err: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(err.errno)
+}
+
+extern "C" {
+    fn setsid() -> i32;
+}
+
+/// Sends the signals that would end sudo to the command instead, so that sudo
+/// stays around to report how it exits.
+///
+/// Called before the command is started, so that no signal can end sudo in
+/// between, `supervise` then tells the handler where to send them.
+fn forward_signals() {
+    for &sig in FORWARDED_SIGNALS {
+        let action = syscall::SigAction {
+            sa_handler: forward_signal,
+            sa_mask: [0; 2],
+            sa_flags: 0,
+        };
+        if let Err(err) = syscall::sigaction(sig, Some(&action), None) {
+            eprintln!("sudo: failed to forward signal {}: {}", sig, err);
+        }
+    }
+}
+
+/// Forwards the signals to `child` from now on, along with the one received while
+/// it was being started.
+fn supervise(child: &Child) {
+    CHILD.store(child.id() as usize, Ordering::SeqCst);
+    let sig = PENDING.swap(0, Ordering::SeqCst);
+    if sig != 0 {
+        let _ = syscall::kill(child.id() as usize, sig);
+    }
+}
+
+extern "C" fn forward_signal(sig: usize) {
+    let pid = CHILD.load(Ordering::SeqCst);
+    if pid != 0 {
+        let _ = syscall::kill(pid, sig);
+    } else {
+        PENDING.store(sig, Ordering::SeqCst);
+    }
+}
+
+/// A session being recorded.
+struct Recording {
+    /// The caller's terminal, opened for the relay.
+    tty: usize,
+    recorder: Recorder,
+}
+
+/// Opens the caller's terminal and creates the session the command is recorded
+/// in, exiting when either fails.
+fn start_recording(io_log: &IoLog, audit: &mut Audit) -> Recording {
+    let tty = current_tty().and_then(|tty| pty::open_tty(&tty).ok()).unwrap_or_else(|| {
+        eprintln!("sudo: '{}' is recorded and needs a terminal", audit.command.join(" "));
+        exit(1);
+    });
+
+    let record = audit.record(Event::Accept);
+    let info = Info {
+        time: record.time,
+        user: record.user,
+        target: record.target,
+        tty: record.tty,
+        cwd: record.cwd,
+        command: record.command,
+    };
+    let (id, recorder) = io_log.create(&info).unwrap_or_else(|err| {
+        eprintln!("sudo: failed to create session log: {}", err);
+        exit(1);
+    });
+    audit.session = Some(id);
+
+    Recording {
+        tty: tty,
+        recorder: recorder,
+    }
+}
+
+/// Runs `command` on a new pseudo terminal, recording the streams `tags` asks for.
+fn run_recorded(command: Command, path: &Path, audit: &Audit, mut recording: Recording, tags: Tags) -> ! {
+    let cmd = path.display();
+    let (columns, lines) = termion::terminal_size().unwrap_or((80, 30));
+    let (master, pty) = pty::getpty(columns as u32, lines as u32).unwrap_or_else(|err| {
+        eprintln!("sudo: failed to create PTY: {}", err);
+        exit(1);
+    });
+
+    forward_signals();
+    let mut child = pty::spawn_on(command, &pty).unwrap_or_else(|err| {
+        eprintln!("sudo: failed to execute {}: {}", cmd, err);
+        exit(1);
+    });
+    supervise(&child);
+
+    // Keys go to the command's terminal as they are typed, it does the echoing
+    let raw = io::stdout().into_raw_mode();
+    let mut failed = false;
+    let result = pty::relay(recording.tty, master, &mut child, |stream, data| {
+        let wanted = match stream {
+            Stream::Input => tags.log_input,
+            Stream::Output => tags.log_output
+        };
+        if wanted && ! failed {
+            if let Err(err) = recording.recorder.record(stream, data) {
+                eprintln!("sudo: failed to record session: {}\r", err);
+                failed = true;
+            }
+        }
+    });
+    drop(raw);
+
+    match result {
+        Ok(status) => {
+            let code = exit_code(status);
+            audit.log(Event::Exit(code));
+            exit(code);
         },
         Err(err) => {
-            eprintln!("sudo: failed to execute {}: {}", cmd, err);
+            eprintln!("sudo: failed to relay {}: {}", cmd, err);
+            let _ = child.kill();
+            let _ = child.wait();
             exit(1);
         }
     }
@@ -0,0 +1,168 @@
+#![deny(warnings)]
+
+extern crate arg_parser;
+extern crate extra;
+extern crate userutils;
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+use arg_parser::ArgParser;
+use extra::option::OptionalExt;
+use userutils::{IoLog, Sudoers};
+use userutils::audit::format_time;
+use userutils::iolog::{Session, Stream};
+
+const MAN_PAGE: &'static str = /* @MANSTART{sudoreplay} */ r#"
+NAME
+    sudoreplay - play back recorded sudo sessions
+
+SYNOPSIS
+    sudoreplay [ -d dir ] [ -s speed ] [ -m max_wait ] session
+    sudoreplay [ -d dir ] -l
+    sudoreplay [ -h | --help ]
+
+DESCRIPTION
+    The sudoreplay utility plays back what was displayed during a session
+    recorded by sudo(1), with the delays it was displayed with. Commands are
+    recorded when the policy tags them LOG_OUTPUT or LOG_INPUT, under the
+    iolog_dir directory of the policy, /var/log/sudo-io by default.
+
+OPTIONS
+
+    -h
+    --help
+        Display this help and exit.
+
+    -l
+    --list
+        List the recorded sessions, with their id, start time, user,
+        terminal, working directory, target and command.
+
+    -d dir
+    --directory=dir
+        Read the sessions from dir instead of the directory of the policy.
+
+    -s speed
+    --speed=speed
+        Divide the delays by speed, 2 plays the session twice as fast.
+
+    -m max_wait
+    --max-wait=max_wait
+        Never wait for more than max_wait seconds between two outputs.
+
+AUTHOR
+    Written by Jose Narvaez.
+"#; /* @MANEND */
+
+pub fn main() {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut stderr = io::stderr();
+
+    let mut parser = ArgParser::new(1)
+        .add_flag(&["h", "help"])
+        .add_flag(&["l", "list"])
+        .add_opt("d", "directory")
+        .add_opt("s", "speed")
+        .add_opt("m", "max-wait");
+    parser.parse(env::args());
+
+    // Shows the help
+    if parser.found("help") {
+        stdout.write_all(MAN_PAGE.as_bytes()).try(&mut stderr);
+        stdout.flush().try(&mut stderr);
+        exit(0);
+    }
+
+    let io_log = match parser.get_opt("directory") {
+        Some(dir) => IoLog::new(dir),
+        None => Sudoers::load().map(|policy| policy.io_log()).unwrap_or_else(|err| {
+            eprintln!("sudoreplay: {}", err);
+            exit(1);
+        })
+    };
+
+    if parser.found("list") {
+        let sessions = io_log.sessions().unwrap_or_else(|err| {
+            eprintln!("sudoreplay: failed to list sessions: {}", err);
+            exit(1);
+        });
+        for session in sessions {
+            let info = &session.info;
+            let line = format!("{} : {} : {} : TTY={} ; PWD={} ; USER={} ; COMMAND={}\n",
+                               session.id, format_time(info.time), info.user,
+                               info.tty.as_ref().map_or("unknown", |tty| tty.as_str()),
+                               info.cwd, info.target, info.command.join(" "));
+            stdout.write_all(line.as_bytes()).try(&mut stderr);
+        }
+        stdout.flush().try(&mut stderr);
+        exit(0);
+    }
+
+    let speed = match parser.get_opt("speed") {
+        Some(speed) => match speed.parse::<f64>() {
+            Ok(speed) if speed > 0.0 => speed,
+            _ => {
+                eprintln!("sudoreplay: invalid speed '{}'", speed);
+                exit(1);
+            }
+        },
+        None => 1.0
+    };
+    let max_wait = parser.get_opt("max-wait").map(|max_wait| match max_wait.parse() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            eprintln!("sudoreplay: invalid maximum wait '{}'", max_wait);
+            exit(1);
+        }
+    });
+
+    let id = parser.args.get(0).unwrap_or_else(|| {
+        eprintln!("sudoreplay: no session provided");
+        exit(1);
+    });
+    let session = io_log.session(id).unwrap_or_else(|err| {
+        eprintln!("sudoreplay: session {}: {}", id, err);
+        exit(1);
+    });
+
+    if let Err(err) = replay(&session, speed, max_wait, &mut stdout) {
+        eprintln!("sudoreplay: session {}: {}", id, err);
+        exit(1);
+    }
+}
+
+/// Writes the output of `session` to `out`, waiting the recorded delays divided by
+/// `speed` and capped at `max_wait`.
+fn replay<W: Write>(session: &Session, speed: f64, max_wait: Option<Duration>, out: &mut W) -> io::Result<()> {
+    let mut output = session.open(Stream::Output)?;
+    let mut buf = Vec::new();
+    let mut delay = Duration::from_secs(0);
+
+    for timing in session.timings()? {
+        // Input is not played back, but the time it took still counts
+        delay += timing.delay;
+        if timing.stream != Stream::Output {
+            continue;
+        }
+
+        let micros = (delay.as_secs() * 1_000_000 + delay.subsec_micros() as u64) as f64 / speed;
+        let mut wait = Duration::from_micros(micros as u64);
+        if let Some(max_wait) = max_wait {
+            wait = wait.min(max_wait);
+        }
+        thread::sleep(wait);
+        delay = Duration::from_secs(0);
+
+        buf.resize(timing.size, 0);
+        output.read_exact(&mut buf)?;
+        out.write_all(&buf)?;
+        out.flush()?;
+    }
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
 #![deny(warnings)]
 
 extern crate arg_parser;
-extern crate redox_users;
+extern crate userutils;
 
 use std::{env, io};
 use std::io::Write;
@@ -10,7 +10,7 @@ use std::os::unix::fs::DirBuilderExt;
 use std::process::exit;
 
 use arg_parser::ArgParser;
-use redox_users::{add_group, add_user, get_unique_group_id, get_unique_user_id};
+use userutils::{AccountDb, FileDb, Group, User};
 
 const MAN_PAGE: &'static str = /* @MANSTART{useradd} */ r#"
 NAME
@@ -48,6 +48,10 @@ OPTIONS
     -N, --no-user-group
         Do not attempt to create the user's user group.
 
+    -R, --root ROOT_DIR
+        Add the user to the account database found under ROOT_DIR
+        instead of the one under /.
+
     -s, --shell SHELL
         The path to the user's default login shell. If left blank, the
         default shell is set as /bin/ion
@@ -68,6 +72,7 @@ fn main() {
         .add_opt("d", "home-dir")
         .add_flag(&["m", "create-home"])
         .add_flag(&["N", "no-user-group"])
+        .add_opt("R", "root")
         .add_opt("s", "shell");
     parser.parse(env::args());
     
@@ -84,22 +89,6 @@ fn main() {
         &parser.args[0]
     };
     
-    let uid = match get_unique_user_id() {
-        Some(id) => id,
-        None => {
-            eprintln!("useradd: no available uid");
-            exit(1);
-        }
-    };
-    
-    let gid = match get_unique_group_id() {
-        Some(id) => id,
-        None => {
-            eprintln!("useradd: no available gid");
-            exit(1);
-        }
-    };
-    
     let username = if parser.found("comment") {
         match parser.get_opt("comment") {
             Some(user) => user,
@@ -136,24 +125,25 @@ fn main() {
         DEFAULT_SHELL.to_string()
     };
     
-    if !parser.found("no-user-group") {
-        match add_group(login, gid, &[login]) {
-            Ok(_) => {},
-            Err(err) => {
-                eprintln!("useradd: error creating group {}: {}", login, err);
-                exit(1);
-            }
-        }
-    }
-    
-    match add_user(login, uid, gid, username.as_str(), userhome.as_str(), shell.as_str()) {
-        Ok(_) => {},
-        Err(err) => {
-            eprintln!("useradd: {}: {}", err, login);
-            exit(1);
-        }
+    let db = match parser.get_opt("root") {
+        Some(root) => FileDb::new(root),
+        None => FileDb::default()
+    };
+
+    // Held from the id allocation until both entries are written, so that
+    // concurrent runs can not be handed the same ids.
+    let lock = db.lock().unwrap_or_else(|err| {
+        eprintln!("useradd: cannot lock account database: {}", err);
+        exit(1);
+    });
+    let result = add_account(&db, login, username, userhome.clone(), shell, !parser.found("no-user-group"));
+    drop(lock);
+
+    if let Err(msg) = result {
+        eprintln!("useradd: {}", msg);
+        exit(1);
     }
-    
+
     if parser.found("create-home") {
         let mut builder = DirBuilder::new();
         builder.mode(DEFAULT_MODE);
@@ -168,3 +158,41 @@ fn main() {
         };
     }
 }
+
+fn add_account(db: &FileDb, login: &str, name: String, home: String, shell: String, user_group: bool) -> Result<(), String> {
+    let uid = db.unique_user_id().ok_or("no available uid")?;
+    let gid = db.unique_group_id().ok_or("no available gid")?;
+
+    if user_group {
+        let group = Group {
+            group: login.to_string(),
+            gid: gid,
+            users: vec![login.to_string()],
+        };
+
+        db.add_group(group)
+            .map_err(|err| format!("error creating group {}: {}", login, err))?;
+    }
+
+    let user = User {
+        user: login.to_string(),
+        hash: String::new(),
+        uid: uid,
+        gid: gid,
+        name: name,
+        home: home,
+        shell: shell,
+    };
+
+    if let Err(err) = db.add_user(user) {
+        // The group was only created for the user
+        if user_group {
+            if let Err(err) = db.remove_group(login) {
+                eprintln!("useradd: failed to remove group {}: {}", login, err);
+            }
+        }
+        return Err(format!("{}: {}", err, login));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,209 @@
+#![deny(warnings)]
+
+extern crate arg_parser;
+extern crate extra;
+extern crate redox_users;
+extern crate userutils;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, exit};
+
+use arg_parser::ArgParser;
+use extra::option::OptionalExt;
+use redox_users::get_uid;
+use userutils::{LOCK_TIMEOUT, Lock, Sudoers};
+use userutils::db::write_atomic;
+use userutils::edit;
+use userutils::sudoers::SUDOERS_FILE;
+
+const MAN_PAGE: &'static str = /* @MANSTART{visudo} */ r#"
+NAME
+    visudo - edit the sudo policy safely
+
+SYNOPSIS
+    visudo [ -f file ]
+    visudo -c [ -f file ]
+    visudo [ -h | --help ]
+
+DESCRIPTION
+    The visudo utility edits the policy of sudo(1), /etc/sudoers. The file
+    is locked against concurrent edits, and a copy of it is opened with the
+    editor named by SUDO_EDITOR, VISUAL or EDITOR.
+
+    Once the editor exits, the copy is parsed the way sudo parses the
+    policy. When it is valid it replaces the policy in one step, so sudo
+    never reads a half written file. Otherwise the error is shown with its
+    line and the copy can be edited again, or dropped leaving the policy
+    unchanged.
+
+    The lock is held by creating the file named after the policy with a
+    .lock suffix. Only root may edit the policy.
+
+OPTIONS
+
+    -h
+    --help
+        Display this help and exit.
+
+    -c
+    --check
+        Only check that the policy parses, and that it belongs to root with
+        mode 0440, without editing it.
+
+    -f file
+    --file=file
+        Edit or check file instead of /etc/sudoers.
+
+EXIT STATUS
+    0 when the policy is valid, or was installed or left unchanged, and >0
+    when it is invalid or could not be edited.
+
+AUTHOR
+    Written by Jose Narvaez.
+"#; /* @MANEND */
+
+/// Mode of a new policy file.
+const SUDOERS_MODE: u32 = 0o440;
+
+pub fn main() {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut stderr = io::stderr();
+
+    let mut parser = ArgParser::new(1)
+        .add_flag(&["h", "help"])
+        .add_flag(&["c", "check"])
+        .add_opt("f", "file");
+    parser.parse(env::args());
+
+    // Shows the help
+    if parser.found("help") {
+        stdout.write_all(MAN_PAGE.as_bytes()).try(&mut stderr);
+        stdout.flush().try(&mut stderr);
+        exit(0);
+    }
+
+    let path = PathBuf::from(parser.get_opt("file").unwrap_or_else(|| SUDOERS_FILE.to_string()));
+
+    if parser.found("check") {
+        let data = fs::read_to_string(&path).unwrap_or_else(|err| {
+            eprintln!("visudo: {}: {}", path.display(), err);
+            exit(1);
+        });
+        if let Err(msg) = check_owner(&path) {
+            eprintln!("visudo: {}: {}", path.display(), msg);
+            exit(1);
+        }
+        match Sudoers::parse(&data) {
+            Ok(_) => println!("{}: parsed OK", path.display()),
+            Err((line, msg)) => {
+                eprintln!("visudo: {}:{}: {}", path.display(), line, msg);
+                exit(1);
+            }
+        }
+        exit(0);
+    }
+
+    if get_uid() != 0 {
+        eprintln!("visudo: only root may edit {}", path.display());
+        exit(1);
+    }
+
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock = Lock::acquire(&lock_path, LOCK_TIMEOUT).unwrap_or_else(|err| {
+        eprintln!("visudo: cannot lock {}: {}", path.display(), err);
+        exit(1);
+    });
+    let result = edit_policy(&path);
+    drop(lock);
+
+    if let Err(msg) = result {
+        eprintln!("visudo: {}", msg);
+        exit(1);
+    }
+}
+
+/// Checks that the policy at `path` belongs to root and has the mode `visudo`
+/// installs it with, so that nobody else may change it.
+fn check_owner(path: &Path) -> Result<(), String> {
+    let metadata = fs::metadata(path).map_err(|err| err.to_string())?;
+    if metadata.uid() != 0 || metadata.gid() != 0 {
+        return Err(format!("wrong owner (uid {}, gid {}), should be (0, 0)", metadata.uid(), metadata.gid()));
+    }
+    if metadata.mode() & 0o7777 != SUDOERS_MODE {
+        return Err(format!("bad permissions {:04o}, should be {:04o}", metadata.mode() & 0o7777, SUDOERS_MODE));
+    }
+    Ok(())
+}
+
+/// Edits a copy of the policy at `path` until it parses or the user gives up,
+/// then installs it.
+fn edit_policy(path: &Path) -> Result<(), String> {
+    let original = edit::read_or_empty(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let copy = edit::temp_copy(path, &original, 0, 0)
+        .map_err(|err| format!("failed to copy {}: {}", path.display(), err))?;
+
+    let result = edit_copy(path, &copy, &original);
+    let _ = fs::remove_file(&copy);
+    result
+}
+
+fn edit_copy(path: &Path, copy: &Path, original: &[u8]) -> Result<(), String> {
+    let editor = edit::editor();
+
+    loop {
+        match Command::new(&editor[0]).args(&editor[1..]).arg(copy).status() {
+            Ok(ref status) if status.success() => (),
+            Ok(status) => return Err(format!("{} exited with {}, {} unchanged", editor[0], status, path.display())),
+            Err(err) => return Err(format!("failed to execute {}: {}", editor[0], err))
+        }
+
+        let data = fs::read(copy).map_err(|err| format!("failed to read {}: {}", copy.display(), err))?;
+        if data == original {
+            eprintln!("visudo: {} unchanged", path.display());
+            return Ok(());
+        }
+
+        let parsed = match String::from_utf8(data.clone()) {
+            Ok(text) => Sudoers::parse(&text).map_err(|(line, msg)| format!("{}:{}: {}", path.display(), line, msg)),
+            Err(_) => Err(format!("{}: not valid UTF-8", path.display()))
+        };
+        match parsed {
+            Ok(_) => {
+                return write_atomic(path, &data, SUDOERS_MODE)
+                    .map_err(|err| format!("failed to write {}: {}", path.display(), err));
+            },
+            Err(msg) => {
+                eprintln!("visudo: {}", msg);
+                if ! ask_again()? {
+                    return Err(format!("{} unchanged", path.display()));
+                }
+            }
+        }
+    }
+}
+
+/// Asks whether to edit again after an error, end of input meaning no.
+fn ask_again() -> Result<bool, String> {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+
+    loop {
+        eprint!("What now? (e)dit again, e(x)it without saving: ");
+        let mut answer = String::new();
+        match stdin.read_line(&mut answer) {
+            Ok(0) => return Ok(false),
+            Ok(_) => match answer.trim() {
+                "e" => return Ok(true),
+                "x" => return Ok(false),
+                _ => ()
+            },
+            Err(err) => return Err(format!("failed to read answer: {}", err))
+        }
+    }
+}
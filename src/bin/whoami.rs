@@ -3,13 +3,15 @@
 extern crate arg_parser;
 extern crate extra;
 extern crate redox_users;
+extern crate userutils;
 
 use std::io::{self, Write};
 use std::process::exit;
 use std::env;
 use arg_parser::ArgParser;
 use extra::option::OptionalExt;
-use redox_users::{get_euid, get_user_by_id};
+use redox_users::get_euid;
+use userutils::{AccountDb, FileDb};
 
 const MAN_PAGE: &'static str = /* @MANSTART{whoami} */ r#"
 NAME
@@ -49,7 +51,7 @@ fn main() {
     }
 
     let euid = get_euid();
-    let user = get_user_by_id(euid).unwrap_or_else(|| {
+    let user = FileDb::default().user_by_id(euid as u32).unwrap_or_else(|| {
         println!("whoami: no user found for uid: {}", euid);
         exit(1);
     });
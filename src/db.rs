@@ -0,0 +1,789 @@
+//! Account database access.
+//!
+//! Every utility looks users and groups up through the `AccountDb` trait instead of
+//! reading the system files directly. `FileDb` is the implementation used on a live
+//! system, it reads and writes the `passwd`, `group` and `shadow` files found under
+//! the `etc` directory of a configurable root (`/` by default).
+//!
+//! When the shadow file exists, password hashes live there instead of in the
+//! world-readable passwd file, whose hash field is then set to `SHADOWED`. Only the
+//! utilities that check passwords need the real hash, they get it with
+//! `AccountDb::with_shadow`.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hash;
+use lock::{Lock, LOCK_TIMEOUT};
+
+/// Path of the passwd file, relative to the database root.
+pub const PASSWD_FILE: &'static str = "etc/passwd";
+/// Path of the group file, relative to the database root.
+pub const GROUP_FILE: &'static str = "etc/group";
+/// Path of the lock file guarding the database, relative to the database root.
+pub const LOCK_FILE: &'static str = "etc/.pwd.lock";
+/// Path of the shadow file, relative to the database root.
+pub const SHADOW_FILE: &'static str = "etc/shadow";
+/// Hash field of passwd entries whose hash is kept in the shadow file.
+pub const SHADOWED: &'static str = "x";
+/// Root used by the utilities when none is given.
+pub const DEFAULT_ROOT: &'static str = "/";
+
+/// Lowest id handed out to new users and groups.
+pub const MIN_ID: u32 = 1000;
+/// Highest id handed out to new users and groups.
+pub const MAX_ID: u32 = 6000;
+
+/// A user entry, maps to a line of the passwd file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    /// Login name.
+    pub user: String,
+    /// Encoded password hash, empty when the user has no password.
+    pub hash: String,
+    /// User id.
+    pub uid: u32,
+    /// Primary group id.
+    pub gid: u32,
+    /// Real name (GECOS field).
+    pub name: String,
+    /// Home directory.
+    pub home: String,
+    /// Login shell.
+    pub shell: String,
+}
+
+impl User {
+    /// Parses a `user;hash;uid;gid;name;home;shell` line.
+    pub fn parse(line: &str) -> Option<User> {
+        let mut parts = line.split(';');
+
+        let user = parts.next()?;
+        let hash = parts.next()?;
+        let uid = parts.next()?.parse().ok()?;
+        let gid = parts.next()?.parse().ok()?;
+        let name = parts.next()?;
+        let home = parts.next()?;
+        let shell = parts.next()?;
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(User {
+            user: user.to_string(),
+            hash: hash.to_string(),
+            uid: uid,
+            gid: gid,
+            name: name.to_string(),
+            home: home.to_string(),
+            shell: shell.to_string(),
+        })
+    }
+
+    /// Checks `password` against the user's stored hash.
+    ///
+    /// A user without a hash only matches the empty password.
+    pub fn verify_passwd(&self, password: &str) -> bool {
+        if self.hash.is_empty() {
+            return password.is_empty();
+        }
+
+        hash::verify(&self.hash, password)
+    }
+}
+
+impl fmt::Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{};{};{};{};{};{};{}",
+               self.user, self.hash, self.uid, self.gid, self.name, self.home, self.shell)
+    }
+}
+
+/// A shadow entry, maps to a line of the shadow file.
+///
+/// Dates are counted in days since the UNIX epoch, empty fields are `None`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shadow {
+    /// Login name.
+    pub user: String,
+    /// Encoded password hash, empty when the user has no password.
+    pub hash: String,
+    /// Date of the last password change.
+    pub last_change: Option<u64>,
+    /// Days before the password may be changed again.
+    pub min: Option<u64>,
+    /// Days after which the password must be changed.
+    pub max: Option<u64>,
+    /// Days of warning before the password expires.
+    pub warn: Option<u64>,
+    /// Days after expiry during which the password is still accepted.
+    pub inactive: Option<u64>,
+    /// Date after which the account can no longer be used.
+    pub expire: Option<u64>,
+}
+
+impl Shadow {
+    /// Creates an entry for `user` with `hash`, changed today and no aging.
+    pub fn new(user: &str, hash: &str) -> Shadow {
+        Shadow {
+            user: user.to_string(),
+            hash: hash.to_string(),
+            last_change: Some(days_since_epoch()),
+            min: None,
+            max: None,
+            warn: None,
+            inactive: None,
+            expire: None,
+        }
+    }
+
+    /// Parses a `user;hash;last_change;min;max;warn;inactive;expire` line.
+    pub fn parse(line: &str) -> Option<Shadow> {
+        fn day(field: &str) -> Option<Option<u64>> {
+            if field.is_empty() {
+                Some(None)
+            } else {
+                field.parse().ok().map(Some)
+            }
+        }
+
+        let mut parts = line.split(';');
+
+        let user = parts.next()?;
+        let hash = parts.next()?;
+        let last_change = day(parts.next()?)?;
+        let min = day(parts.next()?)?;
+        let max = day(parts.next()?)?;
+        let warn = day(parts.next()?)?;
+        let inactive = day(parts.next()?)?;
+        let expire = day(parts.next()?)?;
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(Shadow {
+            user: user.to_string(),
+            hash: hash.to_string(),
+            last_change: last_change,
+            min: min,
+            max: max,
+            warn: warn,
+            inactive: inactive,
+            expire: expire,
+        })
+    }
+}
+
+impl fmt::Display for Shadow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn day(field: Option<u64>) -> String {
+            field.map(|day| day.to_string()).unwrap_or_default()
+        }
+
+        write!(f, "{};{};{};{};{};{};{};{}",
+               self.user, self.hash, day(self.last_change), day(self.min), day(self.max),
+               day(self.warn), day(self.inactive), day(self.expire))
+    }
+}
+
+/// Returns the current date in days since the UNIX epoch.
+pub fn days_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() / 86400)
+        .unwrap_or(0)
+}
+
+/// A group entry, maps to a line of the group file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    /// Group name.
+    pub group: String,
+    /// Group id.
+    pub gid: u32,
+    /// Login names of the group members.
+    pub users: Vec<String>,
+}
+
+impl Group {
+    /// Parses a `group;gid;user,user,...` line.
+    pub fn parse(line: &str) -> Option<Group> {
+        let mut parts = line.split(';');
+
+        let group = parts.next()?;
+        let gid = parts.next()?.parse().ok()?;
+        let users = parts.next()?;
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(Group {
+            group: group.to_string(),
+            gid: gid,
+            users: users.split(',')
+                .filter(|user| ! user.is_empty())
+                .map(|user| user.to_string())
+                .collect(),
+        })
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{};{};{}", self.group, self.gid, self.users.join(","))
+    }
+}
+
+/// Storage for user and group accounts.
+///
+/// Backends only need to load and store whole tables, lookups and modifications
+/// are provided on top of that. Modifying methods fail with `AlreadyExists` when an
+/// entry would clash with an existing one and with `NotFound` when the entry to
+/// change does not exist. They keep hashes in the shadow table whenever the
+/// database has one.
+pub trait AccountDb {
+    /// Returns every user in the database.
+    fn users(&self) -> io::Result<Vec<User>>;
+
+    /// Returns every group in the database.
+    fn groups(&self) -> io::Result<Vec<Group>>;
+
+    /// Replaces the users in the database with `users`.
+    fn save_users(&self, users: &[User]) -> io::Result<()>;
+
+    /// Replaces the groups in the database with `groups`.
+    fn save_groups(&self, groups: &[Group]) -> io::Result<()>;
+
+    /// Takes the lock guarding the database against concurrent modifications.
+    ///
+    /// Callers hold it from the moment they look entries up until they are done
+    /// writing, so that concurrent runs can not lose each other's updates or hand
+    /// out the same id twice.
+    fn lock(&self) -> io::Result<Lock>;
+
+    /// Whether the database keeps hashes in a shadow table.
+    fn has_shadow(&self) -> bool;
+
+    /// Returns every shadow entry, none when there is no shadow table.
+    fn shadows(&self) -> io::Result<Vec<Shadow>>;
+
+    /// Replaces the shadow entries in the database with `shadows`.
+    fn save_shadows(&self, shadows: &[Shadow]) -> io::Result<()>;
+
+    /// Looks the shadow entry of a user up by login name.
+    fn shadow_by_name(&self, name: &str) -> Option<Shadow> {
+        self.shadows().ok()?.into_iter().find(|shadow| shadow.user == name)
+    }
+
+    /// Adds `shadow` or replaces the entry with the same login name.
+    fn update_shadow(&self, shadow: &Shadow) -> io::Result<()> {
+        let mut shadows = self.shadows()?;
+        match shadows.iter().position(|other| other.user == shadow.user) {
+            Some(i) => shadows[i] = shadow.clone(),
+            None => shadows.push(shadow.clone())
+        }
+
+        self.save_shadows(&shadows)
+    }
+
+    /// Returns `user` with its hash taken from the shadow table when it is kept there.
+    ///
+    /// A shadowed user without a shadow entry keeps the `SHADOWED` marker, which no
+    /// password matches.
+    fn with_shadow(&self, mut user: User) -> User {
+        if user.hash == SHADOWED {
+            if let Some(shadow) = self.shadow_by_name(&user.user) {
+                user.hash = shadow.hash;
+            }
+        }
+
+        user
+    }
+
+    /// Looks a user up by login name.
+    fn user_by_name(&self, name: &str) -> Option<User> {
+        self.users().ok()?.into_iter().find(|user| user.user == name)
+    }
+
+    /// Looks a user up by id.
+    fn user_by_id(&self, uid: u32) -> Option<User> {
+        self.users().ok()?.into_iter().find(|user| user.uid == uid)
+    }
+
+    /// Looks a group up by name.
+    fn group_by_name(&self, name: &str) -> Option<Group> {
+        self.groups().ok()?.into_iter().find(|group| group.group == name)
+    }
+
+    /// Looks a group up by id.
+    fn group_by_id(&self, gid: u32) -> Option<Group> {
+        self.groups().ok()?.into_iter().find(|group| group.gid == gid)
+    }
+
+    /// Returns the ids of every group `user` belongs to.
+    ///
+    /// The user's primary group comes first, followed by the groups listing the
+    /// user as a member.
+    fn user_groups(&self, user: &User) -> Vec<u32> {
+        let mut gids = vec![user.gid];
+        if let Ok(groups) = self.groups() {
+            for group in groups {
+                if group.users.iter().any(|name| name == &user.user) && ! gids.contains(&group.gid) {
+                    gids.push(group.gid);
+                }
+            }
+        }
+
+        gids
+    }
+
+    /// Returns the lowest unused user id in the `MIN_ID..MAX_ID` range.
+    fn unique_user_id(&self) -> Option<u32> {
+        let users = self.users().ok()?;
+        (MIN_ID..MAX_ID).find(|uid| ! users.iter().any(|user| user.uid == *uid))
+    }
+
+    /// Returns the lowest unused group id in the `MIN_ID..MAX_ID` range.
+    fn unique_group_id(&self) -> Option<u32> {
+        let groups = self.groups().ok()?;
+        (MIN_ID..MAX_ID).find(|gid| ! groups.iter().any(|group| group.gid == *gid))
+    }
+
+    /// Adds a new user.
+    fn add_user(&self, mut user: User) -> io::Result<()> {
+        let mut users = self.users()?;
+        if users.iter().any(|other| other.user == user.user || other.uid == user.uid) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "user already exists"));
+        }
+
+        if self.has_shadow() {
+            self.update_shadow(&Shadow::new(&user.user, &user.hash))?;
+            user.hash = SHADOWED.to_string();
+        }
+
+        users.push(user);
+        self.save_users(&users)
+    }
+
+    /// Adds a new group.
+    fn add_group(&self, group: Group) -> io::Result<()> {
+        let mut groups = self.groups()?;
+        if groups.iter().any(|other| other.group == group.group || other.gid == group.gid) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "group already exists"));
+        }
+
+        groups.push(group);
+        self.save_groups(&groups)
+    }
+
+    /// Replaces the user with the same login name as `user`.
+    ///
+    /// When the stored entry is shadowed, a changed hash goes to the shadow table.
+    fn update_user(&self, user: &User) -> io::Result<()> {
+        let mut users = self.users()?;
+        if users.iter().any(|other| other.uid == user.uid && other.user != user.user) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "user id already in use"));
+        }
+
+        let mut user = user.clone();
+        match users.iter_mut().find(|other| other.user == user.user) {
+            Some(entry) => {
+                if entry.hash == SHADOWED && user.hash != SHADOWED {
+                    let mut shadow = self.shadow_by_name(&user.user)
+                        .unwrap_or_else(|| Shadow::new(&user.user, ""));
+                    if shadow.hash != user.hash {
+                        shadow.hash = user.hash.clone();
+                        shadow.last_change = Some(days_since_epoch());
+                        self.update_shadow(&shadow)?;
+                    }
+                    user.hash = SHADOWED.to_string();
+                }
+                *entry = user;
+            },
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "user does not exist"))
+        }
+
+        self.save_users(&users)
+    }
+
+    /// Replaces the group with the same name as `group`.
+    fn update_group(&self, group: &Group) -> io::Result<()> {
+        let mut groups = self.groups()?;
+        if groups.iter().any(|other| other.gid == group.gid && other.group != group.group) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "group id already in use"));
+        }
+
+        match groups.iter_mut().find(|other| other.group == group.group) {
+            Some(entry) => *entry = group.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "group does not exist"))
+        }
+
+        self.save_groups(&groups)
+    }
+
+    /// Removes the user with login name `name`.
+    fn remove_user(&self, name: &str) -> io::Result<()> {
+        let mut users = self.users()?;
+        let count = users.len();
+        users.retain(|user| user.user != name);
+        if users.len() == count {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "user does not exist"));
+        }
+
+        self.save_users(&users)?;
+
+        if self.has_shadow() {
+            let mut shadows = self.shadows()?;
+            shadows.retain(|shadow| shadow.user != name);
+            self.save_shadows(&shadows)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the group named `name`.
+    fn remove_group(&self, name: &str) -> io::Result<()> {
+        let mut groups = self.groups()?;
+        let count = groups.len();
+        groups.retain(|group| group.group != name);
+        if groups.len() == count {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "group does not exist"));
+        }
+
+        self.save_groups(&groups)
+    }
+}
+
+/// Account database backed by the `passwd`, `group` and `shadow` files under a root
+/// directory.
+///
+/// # Examples
+///
+/// ```
+/// use userutils::{AccountDb, FileDb};
+///
+/// let db = FileDb::new("/tmp/sysroot");
+/// let user = db.user_by_name("goyox86");
+/// ```
+#[derive(Clone, Debug)]
+pub struct FileDb {
+    root: PathBuf,
+}
+
+impl FileDb {
+    /// Creates a database reading `etc/passwd`, `etc/group` and `etc/shadow` under `root`.
+    pub fn new<P: AsRef<Path>>(root: P) -> FileDb {
+        FileDb { root: root.as_ref().to_path_buf() }
+    }
+
+    /// The directory this database is rooted at.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Full path of the passwd file.
+    pub fn passwd_path(&self) -> PathBuf {
+        self.root.join(PASSWD_FILE)
+    }
+
+    /// Full path of the group file.
+    pub fn group_path(&self) -> PathBuf {
+        self.root.join(GROUP_FILE)
+    }
+
+    /// Full path of the shadow file.
+    pub fn shadow_path(&self) -> PathBuf {
+        self.root.join(SHADOW_FILE)
+    }
+
+    /// Full path of the lock file.
+    pub fn lock_path(&self) -> PathBuf {
+        self.root.join(LOCK_FILE)
+    }
+}
+
+impl Default for FileDb {
+    fn default() -> FileDb {
+        FileDb::new(DEFAULT_ROOT)
+    }
+}
+
+impl AccountDb for FileDb {
+    fn users(&self) -> io::Result<Vec<User>> {
+        read_entries(&self.passwd_path(), User::parse)
+    }
+
+    fn groups(&self) -> io::Result<Vec<Group>> {
+        read_entries(&self.group_path(), Group::parse)
+    }
+
+    fn save_users(&self, users: &[User]) -> io::Result<()> {
+        write_entries(&self.passwd_path(), users, 0o644)
+    }
+
+    fn save_groups(&self, groups: &[Group]) -> io::Result<()> {
+        write_entries(&self.group_path(), groups, 0o644)
+    }
+
+    /// Takes `LOCK_FILE`, waiting up to `LOCK_TIMEOUT` for it.
+    fn lock(&self) -> io::Result<Lock> {
+        Lock::acquire(self.lock_path(), LOCK_TIMEOUT)
+    }
+
+    fn has_shadow(&self) -> bool {
+        self.shadow_path().exists()
+    }
+
+    fn shadows(&self) -> io::Result<Vec<Shadow>> {
+        if ! self.has_shadow() {
+            return Ok(Vec::new());
+        }
+
+        read_entries(&self.shadow_path(), Shadow::parse)
+    }
+
+    fn save_shadows(&self, shadows: &[Shadow]) -> io::Result<()> {
+        write_entries(&self.shadow_path(), shadows, 0o600)
+    }
+}
+
+/// Reads `path` parsing every non empty line with `parse`.
+pub(crate) fn read_entries<T, F: Fn(&str) -> Option<T>>(path: &Path, parse: F) -> io::Result<Vec<T>> {
+    let mut data = String::new();
+    File::open(path)?.read_to_string(&mut data)?;
+
+    let mut entries = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse(line) {
+            Some(entry) => entries.push(entry),
+            None => return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: malformed entry", path.display(), i + 1)
+            ))
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Writes one line per entry to `path`, see `write_atomic`.
+pub(crate) fn write_entries<T: fmt::Display>(path: &Path, entries: &[T], mode: u32) -> io::Result<()> {
+    let mut data = String::new();
+    for entry in entries {
+        data.push_str(&entry.to_string());
+        data.push('\n');
+    }
+
+    write_atomic(path, data.as_bytes(), mode)
+}
+
+/// Replaces the contents of `path` with `data`.
+///
+/// The data goes to a `path+` temporary file first, which then replaces `path`
+/// with a rename, so readers and crashes only ever see the old or the new contents.
+/// The new file keeps the owner and mode of the one it replaces, or gets `mode` if
+/// `path` did not exist.
+pub fn write_atomic(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push("+");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let original = match fs::metadata(path) {
+        Ok(metadata) => Some(metadata),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err)
+    };
+
+    // Left behind by a writer that crashed
+    match fs::remove_file(&tmp_path) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err)
+    }
+
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp_path)?;
+
+        if let Some(ref metadata) = original {
+            unix_fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid()))?;
+            file.set_permissions(fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+        }
+
+        file.write_all(data)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    static NEXT_ROOT: AtomicUsize = AtomicUsize::new(0);
+
+    /// A throwaway root holding `passwd` and `group`, and `shadow` when given.
+    fn root(passwd: &str, group: &str, shadow: Option<&str>) -> FileDb {
+        let root = env::temp_dir().join(format!("userutils-db-{}-{}", ::std::process::id(), NEXT_ROOT.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("etc")).unwrap();
+
+        let db = FileDb::new(&root);
+        fs::write(db.passwd_path(), passwd).unwrap();
+        fs::write(db.group_path(), group).unwrap();
+        if let Some(shadow) = shadow {
+            fs::write(db.shadow_path(), shadow).unwrap();
+        }
+        db
+    }
+
+    fn user(name: &str, uid: u32, hash: &str) -> User {
+        User {
+            user: name.to_string(),
+            hash: hash.to_string(),
+            uid: uid,
+            gid: uid,
+            name: name.to_string(),
+            home: format!("/home/{}", name),
+            shell: "/bin/ion".to_string(),
+        }
+    }
+
+    const PASSWD: &'static str = "root;;0;0;root;/root;/bin/ion\nalice;;1000;1000;Alice;/home/alice;/bin/ion\n";
+    const GROUP: &'static str = "root;0;\nalice;1000;\nsudo;1;alice\nstaff;50;bob,alice\n";
+
+    #[test]
+    fn lookups() {
+        let db = root(PASSWD, GROUP, None);
+        assert_eq!(db.user_by_name("alice").map(|user| user.uid), Some(1000));
+        assert_eq!(db.user_by_id(0).map(|user| user.user), Some("root".to_string()));
+        assert!(db.user_by_name("bob").is_none());
+        assert_eq!(db.group_by_name("staff").map(|group| group.gid), Some(50));
+        assert_eq!(db.group_by_id(1).map(|group| group.users), Some(vec!["alice".to_string()]));
+
+        let alice = db.user_by_name("alice").unwrap();
+        assert_eq!(db.user_groups(&alice), vec![1000, 1, 50]);
+        assert_eq!(db.unique_user_id(), Some(1001));
+        assert_eq!(db.unique_group_id(), Some(1001));
+    }
+
+    #[test]
+    fn malformed_entries_are_reported_with_their_line() {
+        let db = root("root;;0;0;root;/root;/bin/ion\nbroken\n", GROUP, None);
+        let err = db.users().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().ends_with("passwd:2: malformed entry"));
+    }
+
+    #[test]
+    fn add_update_and_remove_users() {
+        let db = root(PASSWD, GROUP, None);
+        db.add_user(user("bob", 1001, "")).unwrap();
+        assert_eq!(db.add_user(user("bob", 1002, "")).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(db.add_user(user("carol", 1000, "")).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        let mut bob = db.user_by_name("bob").unwrap();
+        bob.shell = "/bin/sh".to_string();
+        db.update_user(&bob).unwrap();
+        assert_eq!(db.user_by_name("bob").unwrap().shell, "/bin/sh");
+        assert_eq!(db.update_user(&user("carol", 1003, "")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(db.update_user(&user("bob", 1000, "")).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        db.remove_user("bob").unwrap();
+        assert!(db.user_by_name("bob").is_none());
+        assert_eq!(db.remove_user("bob").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn add_update_and_remove_groups() {
+        let db = root(PASSWD, GROUP, None);
+        db.add_group(Group { group: "build".to_string(), gid: 1001, users: Vec::new() }).unwrap();
+        let clash = Group { group: "other".to_string(), gid: 50, users: Vec::new() };
+        assert_eq!(db.add_group(clash).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        let mut build = db.group_by_name("build").unwrap();
+        build.users.push("alice".to_string());
+        db.update_group(&build).unwrap();
+        assert!(db.user_groups(&db.user_by_name("alice").unwrap()).contains(&1001));
+
+        db.remove_group("build").unwrap();
+        assert!(db.group_by_name("build").is_none());
+        assert_eq!(db.remove_group("build").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn hashes_live_in_the_shadow_file() {
+        let db = root("root;x;0;0;root;/root;/bin/ion\n", "root;0;\n", Some("root;$hash;17000;;;;;\n"));
+        db.add_user(user("bob", 1001, "$bob")).unwrap();
+        assert_eq!(db.user_by_name("bob").unwrap().hash, SHADOWED);
+        assert_eq!(db.with_shadow(db.user_by_name("bob").unwrap()).hash, "$bob");
+
+        let mut bob = db.user_by_name("bob").unwrap();
+        bob.hash = "$new".to_string();
+        db.update_user(&bob).unwrap();
+        assert_eq!(db.user_by_name("bob").unwrap().hash, SHADOWED);
+        assert_eq!(db.shadow_by_name("bob").map(|shadow| shadow.hash), Some("$new".to_string()));
+
+        db.remove_user("bob").unwrap();
+        assert!(db.shadow_by_name("bob").is_none());
+        assert_eq!(db.with_shadow(db.user_by_name("root").unwrap()).hash, "$hash");
+    }
+
+    #[test]
+    fn write_entries_keeps_owner_and_mode() {
+        let db = root(PASSWD, GROUP, None);
+        let path = db.passwd_path();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let owner = fs::metadata(&path).unwrap().uid();
+        // Only root can hand the file to someone else
+        let owner = if owner == 0 && File::open(&path).and_then(|file| unix_fs::fchown(&file, Some(1234), Some(1234))).is_ok() {
+            1234
+        } else {
+            owner
+        };
+
+        db.add_user(user("bob", 1001, "")).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o640);
+        assert_eq!(metadata.uid(), owner);
+
+        let new = db.root().join("etc/new");
+        write_entries(&new, &[user("bob", 1001, "")], 0o600).unwrap();
+        assert_eq!(fs::metadata(&new).unwrap().mode() & 0o7777, 0o600);
+        assert_eq!(fs::read_to_string(&new).unwrap(), "bob;;1001;1001;bob;/home/bob;/bin/ion\n");
+        assert!(! db.root().join("etc/new+").exists());
+    }
+
+    #[test]
+    fn the_lock_is_exclusive() {
+        let db = root(PASSWD, GROUP, None);
+        let lock = db.lock().unwrap();
+        assert_eq!(Lock::acquire(db.lock_path(), Duration::from_millis(200)).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        drop(lock);
+        assert!(Lock::acquire(db.lock_path(), Duration::from_millis(200)).is_ok());
+    }
+}
@@ -0,0 +1,73 @@
+//! Site wide settings read from `/etc/login.defs`.
+//!
+//! The file holds one `KEY value` pair per line, empty lines and lines starting
+//! with `#` are ignored. Unknown keys are kept, so that each utility only looks at
+//! the ones it cares about.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Default location of the settings file.
+pub const LOGIN_DEFS: &'static str = "/etc/login.defs";
+
+/// The settings found in a `login.defs` file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Defs {
+    values: BTreeMap<String, String>,
+}
+
+impl Defs {
+    /// Reads `LOGIN_DEFS`, a missing file has no settings.
+    pub fn load() -> io::Result<Defs> {
+        Defs::load_from(Path::new(LOGIN_DEFS))
+    }
+
+    /// Reads the settings file at `path`, a missing file has no settings.
+    pub fn load_from(path: &Path) -> io::Result<Defs> {
+        let mut data = String::new();
+        match File::open(path) {
+            Ok(mut file) => { file.read_to_string(&mut data)?; },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err)
+        }
+
+        Ok(Defs::parse(&data))
+    }
+
+    /// Parses the contents of a settings file.
+    pub fn parse(data: &str) -> Defs {
+        let mut values = BTreeMap::new();
+        for line in data.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            values.insert(key.to_string(), value.to_string());
+        }
+
+        Defs { values: values }
+    }
+
+    /// The raw value of `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+
+    /// The value of `key` parsed as `T`, `default` when it is not set.
+    pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> io::Result<T> {
+        match self.get(key) {
+            Some(value) => value.parse().map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid value '{}' for {}", value, key)
+            )),
+            None => Ok(default)
+        }
+    }
+}
@@ -0,0 +1,386 @@
+//! Helpers for editing files on behalf of another user, used by `sudoedit` and
+//! `visudo`.
+//!
+//! Files are never handed to the editor directly. They are copied to temporary
+//! files the editing user owns, the editor runs with that user's privileges and
+//! the copies are read back once it exits.
+
+use std::env;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use syscall;
+
+use db::User;
+
+/// Editor used when none of `SUDO_EDITOR`, `VISUAL` and `EDITOR` is set.
+pub const DEFAULT_EDITOR: &'static str = "vi";
+/// Directory the temporary copies are created in.
+pub const TEMP_DIR: &'static str = "/tmp";
+/// Mode of the files that only come to exist by being edited, writable by their
+/// owner and readable by everyone.
+pub const NEW_FILE_MODE: u32 = 0o644;
+
+/// The editor command line chosen by the environment, from `SUDO_EDITOR`,
+/// `VISUAL` or `EDITOR` in that order.
+///
+/// The first word is the program and the next ones its arguments.
+pub fn editor() -> Vec<String> {
+    for var in ["SUDO_EDITOR", "VISUAL", "EDITOR"].iter() {
+        if let Ok(value) = env::var(var) {
+            let words = value.split_whitespace().map(|word| word.to_string()).collect::<Vec<String>>();
+            if ! words.is_empty() {
+                return words;
+            }
+        }
+    }
+
+    vec![DEFAULT_EDITOR.to_string()]
+}
+
+/// Creates a temporary file holding `data` and owned by `uid` and `gid`.
+///
+/// The file is named after `name` so that editors pick the right syntax, and only
+/// its owner may access it.
+pub fn temp_copy(name: &Path, data: &[u8], uid: u32, gid: u32) -> io::Result<PathBuf> {
+    let base = name.file_name()
+        .map(|base| base.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "file".to_string());
+
+    for i in 0.. {
+        let path = Path::new(TEMP_DIR).join(format!("{}.{}.{}", base, process::id(), i));
+        let mut file = match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err)
+        };
+
+        let result = file.write_all(data).and_then(|_| unix_fs::fchown(&file, Some(uid), Some(gid)));
+        if let Err(err) = result {
+            let _ = fs::remove_file(&path);
+            return Err(err);
+        }
+
+        return Ok(path);
+    }
+
+    unreachable!()
+}
+
+/// Reads back a copy made by `temp_copy` for the user `uid`, once the editor exited.
+///
+/// The copy sits in a directory anyone may write to, so the user may have replaced
+/// it by now. Symbolic links are not followed, and the file must be a regular file
+/// owned by `uid` on the file system of the temporary directory, so that it can not
+/// be used to read a file the user has no access to. Its inode is not compared, as
+/// many editors save by renaming a new file over the old one.
+pub fn read_copy(path: &Path, uid: u32) -> io::Result<Vec<u8>> {
+    let mut file = OpenOptions::new().read(true).custom_flags(syscall::O_NOFOLLOW as i32).open(path)?;
+    let metadata = file.metadata()?;
+    let dir = fs::metadata(path.parent().unwrap_or_else(|| Path::new(TEMP_DIR)))?;
+
+    if ! metadata.file_type().is_file() || metadata.uid() != uid || metadata.dev() != dir.dev() {
+        return Err(replaced(path));
+    }
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Writes the edited `data` back to `path` with `write_back`, unless it still is
+/// the `original` contents. Returns whether `path` was written.
+pub fn write_changes(path: &Path, data: &[u8], original: &[u8], mode: u32, uid: u32, gid: u32) -> io::Result<bool> {
+    if data == original {
+        return Ok(false);
+    }
+
+    write_back(path, data, mode, uid, gid)?;
+    Ok(true)
+}
+
+/// Replaces the contents of `path` with `data`, keeping its owner and mode.
+///
+/// A file that did not exist is created with `mode` and owned by `uid` and `gid`.
+/// Refuses to write through symbolic links, and to a file that is not the one
+/// looked at first.
+pub fn write_back(path: &Path, data: &[u8], mode: u32, uid: u32, gid: u32) -> io::Result<()> {
+    let original = match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_symlink() => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is a symbolic link", path.display())
+        )),
+        Ok(metadata) => Some(metadata),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err)
+    };
+
+    // The file may have been replaced since it was looked at, the descriptor tells
+    // what was opened. It is only truncated once known to be the same file
+    let mut file = match original {
+        Some(_) => OpenOptions::new().write(true).custom_flags(syscall::O_NOFOLLOW as i32).open(path)?,
+        None => OpenOptions::new().write(true).create_new(true).mode(mode).custom_flags(syscall::O_NOFOLLOW as i32).open(path)?
+    };
+    if let Some(ref metadata) = original {
+        let opened = file.metadata()?;
+        if ! same_file(&opened, metadata) {
+            return Err(replaced(path));
+        }
+        file.set_len(0)?;
+    }
+
+    let (uid, gid, mode) = match original {
+        Some(ref metadata) => (metadata.uid(), metadata.gid(), metadata.mode() & 0o7777),
+        None => (uid, gid, mode)
+    };
+    unix_fs::fchown(&file, Some(uid), Some(gid))?;
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Whether the user `uid`, member of `gids`, may write to the file or directory
+/// described by `metadata`.
+pub fn writable_by(metadata: &Metadata, uid: u32, gids: &[u32]) -> bool {
+    let mode = metadata.mode();
+    if uid == 0 {
+        return true;
+    }
+    if metadata.uid() == uid {
+        return mode & 0o200 != 0;
+    }
+    if gids.contains(&metadata.gid()) {
+        return mode & 0o020 != 0;
+    }
+    mode & 0o002 != 0
+}
+
+/// Checks that the user `uid`, member of `gids`, may edit `file` on behalf of
+/// `target`, member of `target_gids`. Returns what `file` was found to be, `None`
+/// when it does not exist yet, for `read_checked`.
+///
+/// Refuses files that are symbolic links, that are not regular files, that sit in
+/// a directory the editing user may write to, or that the target could not write.
+pub fn check_editable(file: &Path, uid: u32, gids: &[u32], target: &User, target_gids: &[u32])
+                      -> Result<Option<Metadata>, String> {
+    let dir = file.parent().ok_or_else(|| format!("{}: not a file", file.display()))?;
+    let dir_metadata = fs::metadata(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+
+    // The editing user could swap the file for a link while editing
+    if uid != 0 && writable_by(&dir_metadata, uid, gids) {
+        return Err(format!("{}: editing files in a writable directory is not permitted", file.display()));
+    }
+
+    match fs::symlink_metadata(file) {
+        Ok(ref metadata) if metadata.file_type().is_symlink() => {
+            Err(format!("{}: editing symbolic links is not permitted", file.display()))
+        },
+        Ok(ref metadata) if ! metadata.is_file() => Err(format!("{}: not a regular file", file.display())),
+        Ok(ref metadata) if ! writable_by(metadata, target.uid, target_gids) => {
+            Err(format!("{}: permission denied for '{}'", file.display(), target.user))
+        },
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if writable_by(&dir_metadata, target.uid, target_gids) {
+                Ok(None)
+            } else {
+                Err(format!("{}: permission denied for '{}'", dir.display(), target.user))
+            }
+        },
+        Err(err) => Err(format!("{}: {}", file.display(), err))
+    }
+}
+
+/// Reads the file at `path` that `check_editable` found to be `checked`, a file
+/// that did not exist reads as empty.
+///
+/// Symbolic links are not followed, and the file opened has to be the one that was
+/// checked, so that it can not have been swapped for another one since.
+pub fn read_checked(path: &Path, checked: Option<&Metadata>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match OpenOptions::new().read(true).custom_flags(syscall::O_NOFOLLOW as i32).open(path) {
+        Ok(mut file) => {
+            match checked {
+                Some(checked) if same_file(&file.metadata()?, checked) => (),
+                _ => return Err(replaced(path))
+            }
+            file.read_to_end(&mut data)?;
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound && checked.is_none() => (),
+        Err(err) => return Err(err)
+    }
+    Ok(data)
+}
+
+/// Reads the file at `path`, a missing file reads as empty.
+pub fn read_or_empty(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => { file.read_to_end(&mut data)?; },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err)
+    }
+    Ok(data)
+}
+
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+fn replaced(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{} was replaced by another file", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{DirBuilderExt, symlink};
+    use std::fs::DirBuilder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    /// Someone other than whoever runs the tests, in no group.
+    const OTHER: u32 = 4242;
+
+    /// A throwaway directory with `mode`.
+    fn dir(mode: u32) -> PathBuf {
+        let dir = env::temp_dir().join(format!("userutils-edit-{}-{}", process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(mode)).unwrap();
+        dir
+    }
+
+    /// The owner of `path`, as a target that may write to it.
+    fn owner(path: &Path) -> User {
+        let metadata = fs::metadata(path).unwrap();
+        User {
+            user: "owner".to_string(),
+            hash: String::new(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            name: String::new(),
+            home: String::new(),
+            shell: String::new(),
+        }
+    }
+
+    fn other() -> User {
+        User { uid: OTHER, gid: OTHER, user: "other".to_string(), ..owner(Path::new("/")) }
+    }
+
+    #[test]
+    fn editable_files() {
+        let dir = dir(0o755);
+        let file = dir.join("file");
+        fs::write(&file, "data").unwrap();
+        let target = owner(&dir);
+
+        let checked = check_editable(&file, OTHER, &[], &target, &[]).unwrap().unwrap();
+        assert_eq!(checked.ino(), fs::metadata(&file).unwrap().ino());
+        assert!(check_editable(&dir.join("new"), OTHER, &[], &target, &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn symbolic_links_are_refused() {
+        let dir = dir(0o755);
+        fs::write(dir.join("file"), "data").unwrap();
+        symlink(dir.join("file"), dir.join("link")).unwrap();
+
+        let err = check_editable(&dir.join("link"), OTHER, &[], &owner(&dir), &[]).unwrap_err();
+        assert!(err.contains("symbolic links"), "{}", err);
+    }
+
+    #[test]
+    fn files_in_writable_directories_are_refused() {
+        let dir = dir(0o777);
+        let file = dir.join("file");
+        fs::write(&file, "data").unwrap();
+
+        let err = check_editable(&file, OTHER, &[], &owner(&dir), &[]).unwrap_err();
+        assert!(err.contains("writable directory"), "{}", err);
+        // Through a group too
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o775)).unwrap();
+        assert!(check_editable(&file, OTHER, &[fs::metadata(&dir).unwrap().gid()], &owner(&dir), &[]).is_err());
+        // Root can not be tricked by itself
+        assert!(check_editable(&file, 0, &[], &owner(&dir), &[]).is_ok());
+    }
+
+    #[test]
+    fn files_the_target_can_not_write_are_refused() {
+        let dir = dir(0o755);
+        let file = dir.join("file");
+        fs::write(&file, "data").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let err = check_editable(&file, OTHER, &[], &other(), &[]).unwrap_err();
+        assert!(err.contains("permission denied for 'other'"), "{}", err);
+        assert!(check_editable(&dir.join("new"), OTHER, &[], &other(), &[]).is_err());
+        fs::create_dir(dir.join("sub")).unwrap();
+        let err = check_editable(&dir.join("sub"), OTHER, &[], &owner(&dir), &[]).unwrap_err();
+        assert!(err.contains("not a regular file"), "{}", err);
+    }
+
+    #[test]
+    fn reading_checks_the_file_was_not_swapped() {
+        let dir = dir(0o755);
+        let file = dir.join("file");
+        fs::write(&file, "data").unwrap();
+        let checked = fs::symlink_metadata(&file).unwrap();
+        assert_eq!(read_checked(&file, Some(&checked)).unwrap(), b"data");
+
+        // A new file moved over the checked one
+        fs::write(dir.join("other"), "secret").unwrap();
+        fs::rename(dir.join("other"), &file).unwrap();
+        assert!(read_checked(&file, Some(&checked)).is_err());
+
+        // A link to the checked file, which still exists elsewhere
+        fs::write(dir.join("kept"), "data").unwrap();
+        let checked = fs::symlink_metadata(dir.join("kept")).unwrap();
+        fs::remove_file(&file).unwrap();
+        symlink(dir.join("kept"), &file).unwrap();
+        assert!(read_checked(&file, Some(&checked)).is_err());
+
+        // A file that was missing reads as empty, unless it appeared meanwhile
+        assert_eq!(read_checked(&dir.join("new"), None).unwrap(), b"");
+        assert!(read_checked(&file, None).is_err());
+    }
+
+    #[test]
+    fn only_changes_are_written_back() {
+        let dir = dir(0o755);
+        let file = dir.join("file");
+        fs::write(&file, "data").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        let target = owner(&file);
+
+        // Whatever happened to the file meanwhile is left alone
+        fs::write(&file, "changed meanwhile").unwrap();
+        assert!(! write_changes(&file, b"data", b"data", NEW_FILE_MODE, target.uid, target.gid).unwrap());
+        assert_eq!(fs::read(&file).unwrap(), b"changed meanwhile");
+
+        assert!(write_changes(&file, b"edited", b"data", NEW_FILE_MODE, target.uid, target.gid).unwrap());
+        assert_eq!(fs::read(&file).unwrap(), b"edited");
+        assert_eq!(fs::metadata(&file).unwrap().mode() & 0o7777, 0o640);
+
+        let new = dir.join("new");
+        assert!(write_changes(&new, b"created", b"", NEW_FILE_MODE, target.uid, target.gid).unwrap());
+        assert_eq!(fs::read(&new).unwrap(), b"created");
+        assert_eq!(fs::metadata(&new).unwrap().mode() & 0o7777, NEW_FILE_MODE);
+    }
+
+    #[test]
+    fn links_are_not_written_through() {
+        let dir = dir(0o755);
+        fs::write(dir.join("kept"), "data").unwrap();
+        symlink(dir.join("kept"), dir.join("link")).unwrap();
+
+        assert!(write_back(&dir.join("link"), b"edited", NEW_FILE_MODE, 0, 0).is_err());
+        assert_eq!(fs::read(dir.join("kept")).unwrap(), b"data");
+    }
+}
@@ -0,0 +1,348 @@
+//! Environment of processes started under another identity.
+//!
+//! `login`, `su` and `sudo` never hand the caller's environment as is to the
+//! process they start. By default it is reset: only the variables of the keep-list
+//! survive, `PATH` is replaced by a safe default and the variables describing the
+//! target user are set from its account. The policy is read from `/etc/login.defs`:
+//!
+//! ```text
+//! ENV_RESET   yes
+//! ENV_KEEP    TERM COLORTERM COLUMNS LINES LANG LC_ALL TZ TTY
+//! ENV_SET     EDITOR=/bin/ed PAGER=/bin/less
+//! ENV_PATH    /bin:/usr/bin
+//! ENV_SUPATH  /sbin:/bin:/usr/sbin:/usr/bin
+//! MAIL_DIR    /var/mail
+//! ```
+//!
+//! `ENV_PATH` is used for regular users and `ENV_SUPATH` for root, both may also be
+//! written `PATH=...`. With `ENV_RESET no` the whole environment is kept instead,
+//! except for the variables that change how programs are loaded or how shells
+//! parse their input (`LD_*`, `IFS`, `ENV`...), which are always removed.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::process::Command;
+
+use db::User;
+use defs::Defs;
+use groups_env;
+
+/// `PATH` given to regular users when `ENV_PATH` is not set.
+pub const DEFAULT_PATH: &'static str = "/bin:/usr/bin";
+/// `PATH` given to root when `ENV_SUPATH` is not set.
+pub const DEFAULT_SUPATH: &'static str = "/sbin:/bin:/usr/sbin:/usr/bin";
+/// Directory holding the mailboxes when `MAIL_DIR` is not set.
+pub const DEFAULT_MAIL_DIR: &'static str = "/var/mail";
+/// Variables kept from the caller when `ENV_KEEP` is not set.
+pub const DEFAULT_KEEP: &'static [&'static str] = &[
+    "TERM", "COLORTERM", "COLUMNS", "LINES", "LANG", "LC_ALL", "TZ", "TTY"
+];
+
+/// Variables that are never passed on.
+const UNSAFE_VARS: &'static [&'static str] = &[
+    "IFS", "ENV", "BASH_ENV", "CDPATH", "SHELLOPTS", "PS4", "GLOBIGNORE", "LOCALDOMAIN",
+    "RES_OPTIONS", "HOSTALIASES", "NLSPATH", "PERLLIB", "PERL5LIB", "PERL5OPT",
+    "PYTHONHOME", "PYTHONPATH", "PYTHONSTARTUP", "RUBYLIB", "RUBYOPT"
+];
+/// Prefixes of the variables that are never passed on.
+const UNSAFE_PREFIXES: &'static [&'static str] = &["LD_", "DYLD_", "MALLOC_"];
+
+/// How the environment of a process started under another identity is built.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnvPolicy {
+    /// Whether to start from an empty environment rather than the caller's.
+    pub reset: bool,
+    /// Variables kept from the caller when `reset` is set.
+    pub keep: Vec<String>,
+    /// Variables set on top of the kept ones.
+    pub set: Vec<(String, String)>,
+    /// `PATH` of regular users.
+    pub path: String,
+    /// `PATH` of root.
+    pub supath: String,
+    /// Directory holding the mailboxes, for `MAIL`.
+    pub mail_dir: String,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> EnvPolicy {
+        EnvPolicy {
+            reset: true,
+            keep: DEFAULT_KEEP.iter().map(|name| name.to_string()).collect(),
+            set: Vec::new(),
+            path: DEFAULT_PATH.to_string(),
+            supath: DEFAULT_SUPATH.to_string(),
+            mail_dir: DEFAULT_MAIL_DIR.to_string(),
+        }
+    }
+}
+
+impl EnvPolicy {
+    /// Reads the policy from `/etc/login.defs`, see `EnvPolicy::from_defs`.
+    pub fn load() -> io::Result<EnvPolicy> {
+        EnvPolicy::from_defs(&Defs::load()?)
+    }
+
+    /// Reads the policy from `defs`, keeping the defaults for unset keys.
+    pub fn from_defs(defs: &Defs) -> io::Result<EnvPolicy> {
+        let mut policy = EnvPolicy::default();
+
+        if let Some(value) = defs.get("ENV_RESET") {
+            policy.reset = parse_bool(value).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid value '{}' for ENV_RESET", value)
+            ))?;
+        }
+
+        if let Some(value) = defs.get("ENV_KEEP") {
+            policy.keep = split_list(value).into_iter().map(|name| name.to_string()).collect();
+        }
+
+        if let Some(value) = defs.get("ENV_SET") {
+            for assignment in split_list(value) {
+                let (name, value) = parse_assignment(assignment).ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid assignment '{}' in ENV_SET", assignment)
+                ))?;
+                policy.set.push((name.to_string(), value.to_string()));
+            }
+        }
+
+        if let Some(value) = defs.get("ENV_PATH") {
+            policy.path = value.trim_start_matches("PATH=").to_string();
+        }
+
+        if let Some(value) = defs.get("ENV_SUPATH") {
+            policy.supath = value.trim_start_matches("PATH=").to_string();
+        }
+
+        if let Some(value) = defs.get("MAIL_DIR") {
+            policy.mail_dir = value.to_string();
+        }
+
+        Ok(policy)
+    }
+
+    /// The `PATH` of `user`.
+    pub fn path_for(&self, user: &User) -> &str {
+        if user.uid == 0 { &self.supath } else { &self.path }
+    }
+
+    /// Builds the environment of a process running as `user` with the supplementary
+    /// `groups`, out of the caller's `vars`.
+    ///
+    /// Besides the kept and set variables, `USER`, `LOGNAME`, `UID`, `GROUPS`,
+    /// `HOME`, `SHELL`, `MAIL` and `PATH` always describe `user`. `TERM` describes
+    /// the caller's terminal, so it is kept even when missing from the keep-list,
+    /// unless its value looks like a path.
+    pub fn environment<I>(&self, vars: I, user: &User, groups: &[u32]) -> BTreeMap<String, String>
+        where I: IntoIterator<Item = (String, String)>
+    {
+        let mut env = BTreeMap::new();
+
+        for (name, value) in vars {
+            if is_unsafe(&name) {
+                continue;
+            }
+
+            let keep = if name == "TERM" {
+                ! value.contains('/')
+            } else {
+                ! self.reset || self.keep.iter().any(|kept| kept == &name)
+            };
+
+            if keep {
+                env.insert(name, value);
+            }
+        }
+
+        env.insert("PATH".to_string(), self.path_for(user).to_string());
+
+        for &(ref name, ref value) in self.set.iter() {
+            env.insert(name.clone(), value.clone());
+        }
+
+        env.insert("USER".to_string(), user.user.clone());
+        env.insert("LOGNAME".to_string(), user.user.clone());
+        env.insert("UID".to_string(), user.uid.to_string());
+        env.insert("GROUPS".to_string(), groups_env(groups));
+        env.insert("HOME".to_string(), user.home.clone());
+        env.insert("SHELL".to_string(), user.shell.clone());
+        env.insert("MAIL".to_string(), format!("{}/{}", self.mail_dir.trim_end_matches('/'), user.user));
+
+        env
+    }
+
+    /// Replaces the environment of `command` with the one built for `user` out of
+    /// the current process' environment. Variables that are not valid UTF-8 are
+    /// dropped.
+    pub fn apply(&self, command: &mut Command, user: &User, groups: &[u32]) {
+        command.env_clear();
+        command.envs(self.environment(caller_vars(), user, groups));
+    }
+}
+
+/// The environment of the current process, without the variables that are not
+/// valid UTF-8.
+pub fn caller_vars() -> Vec<(String, String)> {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
+/// Whether the variable `name` is removed whatever the policy says.
+pub fn is_unsafe(name: &str) -> bool {
+    UNSAFE_VARS.contains(&name) || UNSAFE_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+/// Splits a `NAME=value` assignment, `None` when there is no valid name.
+pub fn parse_assignment(assignment: &str) -> Option<(&str, &str)> {
+    let mut parts = assignment.splitn(2, '=');
+    let name = parts.next()?;
+    let value = parts.next()?;
+
+    if is_var_name(name) { Some((name, value)) } else { None }
+}
+
+/// Whether `name` is a valid variable name, letters, digits and `_` not starting
+/// with a digit.
+pub fn is_var_name(name: &str) -> bool {
+    name.chars().next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn split_list(value: &str) -> Vec<&str> {
+    value.split(|c: char| c == ',' || c.is_whitespace()).filter(|item| ! item.is_empty()).collect()
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "yes" | "true" | "1" => Some(true),
+        "no" | "false" | "0" => Some(false),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, uid: u32) -> User {
+        User {
+            user: name.to_string(),
+            hash: String::new(),
+            uid: uid,
+            gid: uid,
+            name: name.to_string(),
+            home: format!("/home/{}", name),
+            shell: "/bin/ion".to_string(),
+        }
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn unsafe_variables() {
+        for name in ["IFS", "ENV", "LD_PRELOAD", "LD_LIBRARY_PATH", "DYLD_INSERT_LIBRARIES", "MALLOC_CHECK_", "PYTHONPATH"].iter() {
+            assert!(is_unsafe(name), "{}", name);
+        }
+        for name in ["PATH", "HOME", "TERM", "LANG", "OLD_PWD", "ENVIRONMENT"].iter() {
+            assert!(! is_unsafe(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unsafe_variables_are_always_stripped() {
+        let caller = vars(&[("LD_PRELOAD", "/tmp/evil.so"), ("IFS", "/"), ("EDITOR", "vi")]);
+        let mut policy = EnvPolicy::default();
+        policy.keep.push("LD_PRELOAD".to_string());
+        policy.keep.push("IFS".to_string());
+
+        let env = policy.environment(caller.clone(), &user("alice", 1000), &[1000]);
+        assert!(! env.contains_key("LD_PRELOAD"));
+        assert!(! env.contains_key("IFS"));
+        assert!(! env.contains_key("EDITOR"));
+
+        policy.reset = false;
+        let env = policy.environment(caller, &user("alice", 1000), &[1000]);
+        assert!(! env.contains_key("LD_PRELOAD"));
+        assert!(! env.contains_key("IFS"));
+        assert_eq!(env["EDITOR"], "vi");
+    }
+
+    #[test]
+    fn term_is_kept_unless_it_looks_like_a_path() {
+        let mut policy = EnvPolicy::default();
+        policy.keep.clear();
+
+        let env = policy.environment(vars(&[("TERM", "xterm-256color")]), &user("alice", 1000), &[]);
+        assert_eq!(env["TERM"], "xterm-256color");
+
+        let env = policy.environment(vars(&[("TERM", "../../tmp/terminfo")]), &user("alice", 1000), &[]);
+        assert!(! env.contains_key("TERM"));
+    }
+
+    #[test]
+    fn keep_set_and_account_precedence() {
+        let caller = vars(&[
+            ("LANG", "fr_FR.UTF-8"), ("EDITOR", "nano"), ("HOME", "/home/mallory"), ("PATH", "/tmp"), ("PAGER", "cat")
+        ]);
+        let mut policy = EnvPolicy::default();
+        policy.keep.push("EDITOR".to_string());
+        policy.keep.push("HOME".to_string());
+        policy.keep.push("PATH".to_string());
+        policy.set.push(("EDITOR".to_string(), "/bin/ed".to_string()));
+        policy.set.push(("USER".to_string(), "mallory".to_string()));
+
+        let env = policy.environment(caller.clone(), &user("alice", 1000), &[1000, 50]);
+        // Kept from the caller
+        assert_eq!(env["LANG"], "fr_FR.UTF-8");
+        assert!(! env.contains_key("PAGER"));
+        // Set by the policy over what was kept
+        assert_eq!(env["EDITOR"], "/bin/ed");
+        // Describing the account whatever was kept or set
+        assert_eq!(env["HOME"], "/home/alice");
+        assert_eq!(env["USER"], "alice");
+        assert_eq!(env["LOGNAME"], "alice");
+        assert_eq!(env["UID"], "1000");
+        assert_eq!(env["GROUPS"], "1000 50");
+        assert_eq!(env["SHELL"], "/bin/ion");
+        assert_eq!(env["MAIL"], "/var/mail/alice");
+        assert_eq!(env["PATH"], DEFAULT_PATH);
+
+        let env = policy.environment(caller, &user("root", 0), &[0]);
+        assert_eq!(env["PATH"], DEFAULT_SUPATH);
+    }
+
+    #[test]
+    fn policy_from_defs() {
+        assert_eq!(EnvPolicy::from_defs(&Defs::parse("")).unwrap(), EnvPolicy::default());
+
+        let policy = EnvPolicy::from_defs(&Defs::parse(
+            "ENV_RESET no\nENV_KEEP TERM, LANG\nENV_SET EDITOR=/bin/ed PAGER=\nENV_PATH PATH=/bin\nENV_SUPATH /sbin:/bin\nMAIL_DIR /var/spool/mail/\n"
+        )).unwrap();
+        assert!(! policy.reset);
+        assert_eq!(policy.keep, vec!["TERM".to_string(), "LANG".to_string()]);
+        assert_eq!(policy.set, vec![("EDITOR".to_string(), "/bin/ed".to_string()), ("PAGER".to_string(), String::new())]);
+        assert_eq!(policy.path, "/bin");
+        assert_eq!(policy.supath, "/sbin:/bin");
+        assert_eq!(policy.environment(Vec::new(), &user("alice", 1000), &[])["MAIL"], "/var/spool/mail/alice");
+
+        assert!(EnvPolicy::from_defs(&Defs::parse("ENV_RESET maybe")).is_err());
+        assert!(EnvPolicy::from_defs(&Defs::parse("ENV_SET 1X=y")).is_err());
+        assert!(EnvPolicy::from_defs(&Defs::parse("ENV_SET EDITOR")).is_err());
+    }
+
+    #[test]
+    fn assignments() {
+        assert_eq!(parse_assignment("A=b=c"), Some(("A", "b=c")));
+        assert_eq!(parse_assignment("_x1="), Some(("_x1", "")));
+        assert_eq!(parse_assignment("A"), None);
+        assert_eq!(parse_assignment("=b"), None);
+        assert_eq!(parse_assignment("1A=b"), None);
+        assert_eq!(parse_assignment("A-B=c"), None);
+    }
+}
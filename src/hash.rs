@@ -0,0 +1,199 @@
+//! Password hashing.
+//!
+//! Hashes are stored in the PHC string format:
+//!
+//! ```text
+//! $argon2i$v=16$m=4096,t=10,p=1$<salt>$<hash>
+//! ```
+//!
+//! where `v` is the Argon2 version implemented by `argon2rs`, `m` the memory cost in
+//! KiB, `t` the time cost and `p` the parallelism, salt and hash being base64 encoded
+//! without padding. New hashes use the costs configured in `/etc/login.defs` through
+//! the `ARGON2_MEMORY_COST`, `ARGON2_TIME_COST` and `ARGON2_PARALLELISM` keys.
+//!
+//! Hashes written before the version field was recorded, as `redox_users` did, are
+//! still accepted by `verify` and reported by `needs_rehash`.
+
+use std::io;
+
+use argon2rs::{Argon2, Variant};
+use argon2rs::verifier::Encoded;
+use rand::{OsRng, Rng};
+
+use defs::Defs;
+
+/// Version of the Argon2 algorithm implemented by `argon2rs`.
+pub const ARGON2_VERSION: u32 = 0x10;
+/// Length in bytes of the salts generated for new hashes.
+pub const SALT_LEN: usize = 16;
+
+/// Argon2 cost parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashParams {
+    /// Memory cost in KiB.
+    pub memory: u32,
+    /// Number of passes.
+    pub time: u32,
+    /// Number of lanes.
+    pub parallelism: u32,
+}
+
+impl Default for HashParams {
+    fn default() -> HashParams {
+        HashParams {
+            memory: 4096,
+            time: 10,
+            parallelism: 1,
+        }
+    }
+}
+
+impl HashParams {
+    /// Reads the parameters from `/etc/login.defs`, see `HashParams::from_defs`.
+    pub fn load() -> io::Result<HashParams> {
+        HashParams::from_defs(&Defs::load()?)
+    }
+
+    /// Reads the parameters from `defs`, keeping the defaults for unset keys.
+    pub fn from_defs(defs: &Defs) -> io::Result<HashParams> {
+        let default = HashParams::default();
+        let params = HashParams {
+            memory: defs.get_or("ARGON2_MEMORY_COST", default.memory)?,
+            time: defs.get_or("ARGON2_TIME_COST", default.time)?,
+            parallelism: defs.get_or("ARGON2_PARALLELISM", default.parallelism)?,
+        };
+
+        params.argon2()?;
+        Ok(params)
+    }
+
+    fn argon2(&self) -> io::Result<Argon2> {
+        Argon2::new(self.time, self.parallelism, self.memory, Variant::Argon2i)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid argon2 parameters: {:?}", err)))
+    }
+}
+
+/// Hashes `password` with a fresh random salt.
+pub fn hash_password(password: &str, params: &HashParams) -> io::Result<String> {
+    let mut salt = [0; SALT_LEN];
+    OsRng::new()?.fill_bytes(&mut salt);
+
+    let encoded = Encoded::new(params.argon2()?, password.as_bytes(), &salt, &[], &[]);
+    let legacy = String::from_utf8(encoded.to_u8())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(legacy.replacen("$m=", &format!("$v={}$m=", ARGON2_VERSION), 1))
+}
+
+/// Checks `password` against `hash`, in either the current or the legacy format.
+pub fn verify(hash: &str, password: &str) -> bool {
+    let legacy = match strip_version(hash) {
+        Some(legacy) => legacy,
+        None => return false
+    };
+
+    match Encoded::from_u8(legacy.as_bytes()) {
+        Ok(encoded) => encoded.verify(password.as_bytes()),
+        Err(_) => false
+    }
+}
+
+/// Whether `hash` should be replaced by a new one made with `params`.
+///
+/// That is the case for legacy hashes and for hashes made with other costs.
+pub fn needs_rehash(hash: &str, params: &HashParams) -> bool {
+    if ! hash.starts_with("$argon2i$v=") {
+        return true;
+    }
+
+    let costs = match hash.split('$').nth(3) {
+        Some(costs) => costs,
+        None => return true
+    };
+
+    costs != format!("m={},t={},p={}", params.memory, params.time, params.parallelism)
+}
+
+/// Turns a PHC string into the format `argon2rs` parses, which has no version field.
+///
+/// Returns `None` when the version is not the one `argon2rs` implements.
+fn strip_version(hash: &str) -> Option<String> {
+    let mut parts = hash.splitn(4, '$');
+    let (empty, variant, version) = (parts.next()?, parts.next()?, parts.next()?);
+
+    if ! version.starts_with("v=") {
+        return Some(hash.to_string());
+    }
+
+    if version != format!("v={}", ARGON2_VERSION) {
+        return None;
+    }
+
+    Some(format!("{}${}${}", empty, variant, parts.next()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap costs, so that the tests run fast.
+    const PARAMS: HashParams = HashParams { memory: 64, time: 2, parallelism: 1 };
+
+    /// "correct horse" as `redox_users` stored it, without a version field.
+    const LEGACY: &'static str = "$argon2i$m=64,t=2,p=1$c2FsdHNhbHRzYWx0c2FsdA$ZcQyk0S+ej2vW2IQTRjd2wAwveXbpg7+bYvjjB4Ay7M";
+
+    #[test]
+    fn hashes_round_trip() {
+        let hash = hash_password("correct horse", &PARAMS).unwrap();
+        assert!(hash.starts_with("$argon2i$v=16$m=64,t=2,p=1$"), "{}", hash);
+        assert!(verify(&hash, "correct horse"));
+        assert!(! verify(&hash, "correct horse "));
+        assert!(! verify(&hash, ""));
+
+        // Fresh salts every time
+        assert!(hash != hash_password("correct horse", &PARAMS).unwrap());
+    }
+
+    #[test]
+    fn legacy_hashes_verify() {
+        assert!(verify(LEGACY, "correct horse"));
+        assert!(! verify(LEGACY, "battery staple"));
+
+        // The same hash with the version field verifies too
+        let current = LEGACY.replacen("$m=", "$v=16$m=", 1);
+        assert!(verify(&current, "correct horse"));
+    }
+
+    #[test]
+    fn unknown_hashes_do_not_verify() {
+        assert!(! verify(&LEGACY.replacen("$m=", "$v=19$m=", 1), "correct horse"));
+        assert!(! verify("", ""));
+        assert!(! verify("x", "x"));
+        assert!(! verify("$argon2i$v=16", ""));
+    }
+
+    #[test]
+    fn outdated_hashes_need_a_rehash() {
+        let hash = hash_password("correct horse", &PARAMS).unwrap();
+        assert!(! needs_rehash(&hash, &PARAMS));
+        assert!(needs_rehash(&hash, &HashParams { time: 3, ..PARAMS }));
+        assert!(needs_rehash(&hash, &HashParams { memory: 128, ..PARAMS }));
+        assert!(needs_rehash(&hash, &HashParams { parallelism: 2, ..PARAMS }));
+
+        assert!(needs_rehash(LEGACY, &PARAMS));
+        assert!(needs_rehash("", &PARAMS));
+        assert!(needs_rehash("$argon2i$v=16", &PARAMS));
+    }
+
+    #[test]
+    fn params_from_defs() {
+        assert_eq!(HashParams::from_defs(&Defs::parse("")).unwrap(), HashParams::default());
+
+        let defs = Defs::parse("# costs\nARGON2_MEMORY_COST 8192\nARGON2_TIME_COST\t3\n");
+        assert_eq!(HashParams::from_defs(&defs).unwrap(), HashParams { memory: 8192, time: 3, parallelism: 1 });
+
+        assert!(HashParams::from_defs(&Defs::parse("ARGON2_TIME_COST many")).is_err());
+        assert!(HashParams::from_defs(&Defs::parse("ARGON2_PARALLELISM 0")).is_err());
+        assert!(HashParams::from_defs(&Defs::parse("ARGON2_MEMORY_COST 1")).is_err());
+    }
+}
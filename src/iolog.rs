@@ -0,0 +1,318 @@
+//! Recordings of the terminal sessions of `sudo` commands.
+//!
+//! Each session gets a directory of its own under the I/O log directory, named
+//! after a sequence number, holding:
+//!
+//! - `log`: the start time, the invoking user, the target, the terminal and working
+//!   directory, then the command line, one argument per line.
+//! - `timing`: one `stream delay size` line per chunk of data, `stream` being `i`
+//!   for what was typed and `o` for what was displayed, and `delay` the seconds
+//!   elapsed since the previous chunk.
+//! - `ttyin` and `ttyout`: the data typed and displayed, back to back.
+//!
+//! Everything is readable by root only.
+
+use std::fmt;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use db::read_entries;
+
+/// Default location of the recordings.
+pub const IOLOG_DIR: &'static str = "/var/log/sudo-io";
+
+/// A direction of the terminal traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    /// Typed by the user.
+    Input,
+    /// Displayed by the command.
+    Output,
+}
+
+impl Stream {
+    fn code(&self) -> &'static str {
+        match *self {
+            Stream::Input => "i",
+            Stream::Output => "o"
+        }
+    }
+
+    fn file_name(&self) -> &'static str {
+        match *self {
+            Stream::Input => "ttyin",
+            Stream::Output => "ttyout"
+        }
+    }
+}
+
+/// What a session ran, from its `log` file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Info {
+    /// Start of the session in seconds since the UNIX epoch.
+    pub time: u64,
+    /// The invoking user.
+    pub user: String,
+    /// The target, as `user` or `user:group`.
+    pub target: String,
+    /// The terminal of the invoking user, if any.
+    pub tty: Option<String>,
+    /// Working directory of the invoking user.
+    pub cwd: String,
+    /// The command and its arguments.
+    pub command: Vec<String>,
+}
+
+impl Info {
+    /// Parses the contents of a `log` file.
+    pub fn parse(data: &str) -> Option<Info> {
+        let mut lines = data.lines();
+        let mut header = lines.next()?.splitn(4, ';');
+        let time = header.next()?.parse().ok()?;
+        let user = header.next()?;
+        let target = header.next()?;
+        let tty = header.next()?;
+        let cwd = unescape(lines.next()?);
+
+        Some(Info {
+            time: time,
+            user: user.to_string(),
+            target: target.to_string(),
+            tty: if tty.is_empty() { None } else { Some(tty.to_string()) },
+            cwd: cwd,
+            command: lines.map(unescape).collect(),
+        })
+    }
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{};{};{};{}", self.time, self.user, self.target, self.tty.as_ref().map_or("", |tty| tty.as_str()))?;
+        writeln!(f, "{}", escape(&self.cwd))?;
+        for arg in self.command.iter() {
+            writeln!(f, "{}", escape(arg))?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of a `timing` file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub stream: Stream,
+    /// Time elapsed since the previous chunk.
+    pub delay: Duration,
+    /// Number of bytes of the chunk.
+    pub size: usize,
+}
+
+impl Timing {
+    /// Parses a timing line.
+    pub fn parse(line: &str) -> Option<Timing> {
+        let mut parts = line.split_whitespace();
+        let stream = match parts.next()? {
+            "i" => Stream::Input,
+            "o" => Stream::Output,
+            _ => return None
+        };
+
+        let mut delay = parts.next()?.splitn(2, '.');
+        let secs = delay.next()?.parse().ok()?;
+        let fraction = delay.next().unwrap_or("0");
+        if fraction.len() > 9 || ! fraction.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let nanos = format!("{:0<9}", fraction).parse().ok()?;
+
+        Some(Timing {
+            stream: stream,
+            delay: Duration::new(secs, nanos),
+            size: parts.next()?.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}.{:06} {}", self.stream.code(), self.delay.as_secs(), self.delay.subsec_micros(), self.size)
+    }
+}
+
+/// Records a session as it goes.
+#[derive(Debug)]
+pub struct Recorder {
+    timing: File,
+    input: File,
+    output: File,
+    last: Instant,
+}
+
+impl Recorder {
+    /// Appends `data` to `stream`.
+    pub fn record(&mut self, stream: Stream, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let timing = Timing {
+            stream: stream,
+            delay: now.duration_since(self.last),
+            size: data.len(),
+        };
+        self.last = now;
+
+        match stream {
+            Stream::Input => self.input.write_all(data)?,
+            Stream::Output => self.output.write_all(data)?
+        }
+        writeln!(self.timing, "{}", timing)
+    }
+}
+
+/// A recorded session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    /// Name of the session directory.
+    pub id: String,
+    pub info: Info,
+    dir: PathBuf,
+}
+
+impl Session {
+    /// The chunks of the session, in order.
+    pub fn timings(&self) -> io::Result<Vec<Timing>> {
+        read_entries(&self.dir.join("timing"), Timing::parse)
+    }
+
+    /// Opens the data of `stream`, to be read along `timings`.
+    pub fn open(&self, stream: Stream) -> io::Result<File> {
+        File::open(self.dir.join(stream.file_name()))
+    }
+}
+
+/// The directory holding the recordings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IoLog {
+    dir: PathBuf,
+}
+
+impl IoLog {
+    /// Uses the recordings of `dir`.
+    pub fn new<P: AsRef<Path>>(dir: P) -> IoLog {
+        IoLog { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// Starts recording a new session described by `info`, returning its id.
+    pub fn create(&self, info: &Info) -> io::Result<(String, Recorder)> {
+        if ! self.dir.exists() {
+            DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+        }
+
+        // Creating the directory claims the id, concurrent sessions pick the next one
+        let mut next = self.ids()?.into_iter().map(|(number, _)| number).max().map_or(1, |number| number + 1);
+        let (id, dir) = loop {
+            let id = format!("{:06}", next);
+            let dir = self.dir.join(&id);
+            match DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => break (id, dir),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => next += 1,
+                Err(err) => return Err(err)
+            }
+        };
+
+        create_file(&dir.join("log"))?.write_all(info.to_string().as_bytes())?;
+        let recorder = Recorder {
+            timing: create_file(&dir.join("timing"))?,
+            input: create_file(&dir.join(Stream::Input.file_name()))?,
+            output: create_file(&dir.join(Stream::Output.file_name()))?,
+            last: Instant::now(),
+        };
+
+        Ok((id, recorder))
+    }
+
+    /// The recorded sessions, oldest first.
+    pub fn sessions(&self) -> io::Result<Vec<Session>> {
+        let mut ids = self.ids()?;
+        ids.sort();
+        ids.into_iter()
+            .map(|(_, id)| self.session(&id))
+            .collect()
+    }
+
+    /// The session named `id`.
+    pub fn session(&self, id: &str) -> io::Result<Session> {
+        if id.is_empty() || ! id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid session id '{}'", id)));
+        }
+
+        let dir = self.dir.join(id);
+        let path = dir.join("log");
+        let mut data = String::new();
+        File::open(&path)?.read_to_string(&mut data)?;
+        let info = Info::parse(&data).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: invalid session log", path.display())
+        ))?;
+
+        Ok(Session {
+            id: id.to_string(),
+            info: info,
+            dir: dir,
+        })
+    }
+
+    /// Sequence numbers of the sessions along with the names of their directories,
+    /// a missing directory has none.
+    fn ids(&self) -> io::Result<Vec<(u64, String)>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err)
+        };
+
+        let mut ids = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            let name = match name.to_str() {
+                Some(name) if ! name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) => name,
+                _ => continue
+            };
+            if let Ok(number) = name.parse() {
+                ids.push((number, name.to_string()));
+            }
+        }
+        Ok(ids)
+    }
+}
+
+fn create_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+/// Escapes `\` and newlines, so that a value fits on a line.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(next) => unescaped.push(next),
+            None => unescaped.push(c)
+        }
+    }
+    unescaped
+}
@@ -12,62 +12,168 @@
 //! - `id`: Displays user identity.
 //! - `login`: Allows users to into the system.
 //! - `passwd`: Allows users to modify their passwords.
+//! - `pwconv`: Moves password hashes into the root-only shadow file.
+//! - `pwunconv`: Moves password hashes back into the passwd file.
 //! - `su`: Allows users to substitute identity.
 //! - `sudo`: Enables users to execute a command as another user.
+//! - `sudoedit`: Link to `sudo`, edits files as another user.
+//! - `sudoreplay`: Lists and plays back the sessions recorded by `sudo`.
+//! - `visudo`: Edits the `sudo` policy safely.
 //! - `whoami`: Display effective user ID.
+//!
+//! Users and groups are accessed through the `AccountDb` trait, so the same logic
+//! can run against the live system files or any other tree via `FileDb`. Which
+//! credentials each utility asks for is decided by its `AuthStack`, and the
+//! environment of the processes they start by the `EnvPolicy`. What `sudo` lets
+//! each user run is decided by the `Sudoers` policy, and what it did is kept in
+//! the `AuditLog` and, for the commands the policy asks for, the `IoLog`.
+
+extern crate argon2rs;
+extern crate rand;
+extern crate redox_termios;
+extern crate syscall;
+extern crate termion;
+
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+
+pub mod audit;
+pub mod auth;
+pub mod db;
+pub mod defs;
+pub mod edit;
+pub mod environ;
+pub mod hash;
+pub mod iolog;
+pub mod lock;
+pub mod pty;
+pub mod stack;
+pub mod sudoers;
+pub mod timestamp;
+
+pub use audit::{AuditLog, LogFormat};
+pub use auth::{AuthResult, FAIL_DELAY, MAX_ATTEMPTS, PasswordSource, authenticate, read_password};
+pub use db::{AccountDb, FileDb, Group, Shadow, User};
+pub use defs::Defs;
+pub use environ::EnvPolicy;
+pub use hash::HashParams;
+pub use iolog::IoLog;
+pub use lock::{Lock, LOCK_TIMEOUT};
+pub use stack::{AuthContext, AuthStack, Phase};
+pub use sudoers::Sudoers;
+pub use timestamp::Timestamps;
+
+/// Builds the command used to start the shell of the given `User`.
+///
+/// The new the shell process will have set the users UID and GID, the supplementary
+/// groups in `groups` (see `AccountDb::user_groups`) and its CWD will be set to the
+/// users's home directory. Its environment is built by `env`, which among others
+/// sets `USER`, `LOGNAME`, `UID`, `GROUPS`, `HOME` and `SHELL` for the user.
+///
+/// When `login` is true the shell gets `-shellname` as its `argv[0]`, which is how
+/// shells recognise they are being started as login shells.
+pub fn shell_command(user: &User, groups: &[u32], login: bool, env: &EnvPolicy) -> Command {
+    let mut command = Command::new(&user.shell);
+
+    if login {
+        let name = Path::new(&user.shell)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| user.shell.clone());
+        command.arg0(format!("-{}", name));
+    }
+
+    set_credentials(&mut command, user.uid, user.gid, groups);
 
-extern crate redox_users;
+    command.current_dir(&user.home);
 
-use std::process::Command;
-use std::os::unix::process::CommandExt;
+    env.apply(&mut command, user, groups);
 
-use redox_users::User;
+    command
+}
 
-/// Spawns a shell for the given `User`.
+/// Makes the process `command` starts run as `uid` and `gid`, with the
+/// supplementary groups in `groups`.
 ///
-/// The new the shell process will have set the users UID and GID, its CWD will be
-/// set to the users's home directory and the follwing enviroment variables will
-/// be populated like so:
+/// The standard library leaves supplementary groups alone on Redox, so all three
+/// are set by the child itself, groups first while it still runs as root. The
+/// spawn fails when any of them can not be set.
+pub fn set_credentials(command: &mut Command, uid: u32, gid: u32, groups: &[u32]) {
+    let groups = groups.to_vec();
+    unsafe {
+        command.pre_exec(move || {
+            if setgroups(groups.len(), groups.as_ptr()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            syscall::setregid(gid as usize, gid as usize).map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+            syscall::setreuid(uid as usize, uid as usize).map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+            Ok(())
+        });
+    }
+}
+
+extern "C" {
+    fn setgroups(size: usize, list: *const u32) -> i32;
+}
+
+/// Spawns a shell for the given `User` and waits for it to finish.
 ///
-///    - `USER` set to the user's `user` field.
-///    - `UID` set to the user's `uid` field.
-///    - `GROUPS` set the user's `gid` field.
-///    - `HOME` set to the user's `home` field.
-///    - `SHELL` set to the user's `shell` field.
+/// See `shell_command` for how the shell process is set up.
 ///
 /// # Examples
 ///
-/// ```
-/// use redox_users::get_user_by_name;
+/// ```no_run
+/// use userutils::{AccountDb, EnvPolicy, FileDb, spawn_shell};
 ///
-/// let user = get_user_by_name("goyox86");
-/// spawn_shell(user);
+/// let db = FileDb::default();
+/// let user = db.user_by_name("goyox86").unwrap();
+/// let env = EnvPolicy::load().unwrap();
+/// let status = spawn_shell(&user, &db.user_groups(&user), false, &env).unwrap();
 /// ```
 ///
-/// # Panics
+/// # Errors
 ///
-/// This function can panic under two scenarios. The first, when an error occurs while
-/// spawning the new process containig the shell and the second, when after a succesful
-/// spawn, an error happens while trying to wait for the newly created process.
-pub fn spawn_shell(user: User) {
-    let mut command = Command::new(&user.shell);
+/// Fails when the shell can not be spawned or when waiting for it fails.
+pub fn spawn_shell(user: &User, groups: &[u32], login: bool, env: &EnvPolicy) -> io::Result<ExitStatus> {
+    shell_command(user, groups, login, env).spawn()?.wait()
+}
+
+/// Replaces the current process with a login shell for the given `User`.
+///
+/// This only returns if the shell could not be executed, the returned error tells why.
+pub fn exec_shell(user: &User, groups: &[u32], env: &EnvPolicy) -> io::Error {
+    shell_command(user, groups, true, env).exec()
+}
 
-    command.uid(user.uid);
-    command.gid(user.gid);
+/// Formats group ids the way they are exported in the `GROUPS` variable.
+pub fn groups_env(groups: &[u32]) -> String {
+    groups.iter()
+        .map(|gid| gid.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
 
-    command.current_dir(&user.home);
+/// Returns the path of the terminal on stdin, if any.
+///
+/// `None` when stdin is not a terminal, for example a pipe or a file.
+pub fn current_tty() -> Option<String> {
+    if ! termion::is_tty(&io::stdin()) {
+        return None;
+    }
+
+    let mut buf = [0; 4096];
+    let count = syscall::fpath(0, &mut buf).ok()?;
+    String::from_utf8(buf[..count].to_vec()).ok()
+}
 
-    command.env("USER", &user.user);
-    command.env("UID", format!("{}", user.uid));
-    command.env("GROUPS", format!("{}", user.gid));
-    command.env("HOME", &user.home);
-    command.env("SHELL", &user.shell);
-
-    match command.spawn() {
-        Ok(mut child) => match child.wait() {
-            Ok(_status) => (),
-            Err(err) => panic!("userutils: failed to wait for '{}': {}", user.shell, err)
-        },
-        Err(err) => panic!("userutils: failed to execute '{}': {}", user.shell, err)
+/// Converts a child's `ExitStatus` into the code the parent should exit with.
+///
+/// Processes killed by a signal map to `128 + signal`, as shells do.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0)
     }
 }
@@ -0,0 +1,232 @@
+//! Lock files.
+//!
+//! A lock is held by creating its file exclusively and writing the holder's PID in
+//! it, the file is removed when the `Lock` is dropped. A lock whose holder is no
+//! longer running is considered stale and broken by the next process trying to
+//! take it, however long it was held. A lock file still without a PID after
+//! `STALE_AGE` is stale too, its holder died while creating it.
+//!
+//! Note that `std::process::exit` does not run destructors, so a process has to
+//! drop its locks before exiting. Stale detection only exists as a safety net for
+//! crashed holders.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use syscall;
+
+/// How long the utilities wait for a lock before giving up.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(15);
+/// Age after which a lock file without a PID is considered stale.
+pub const STALE_AGE: Duration = Duration::from_secs(600);
+/// Delay between two attempts at taking a lock.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A held lock file, released on drop.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Takes the lock at `path`, waiting up to `timeout` for its holder to release it.
+    ///
+    /// Fails with `TimedOut` when the lock could not be taken in time.
+    pub fn acquire<P: AsRef<Path>>(path: P, timeout: Duration) -> io::Result<Lock> {
+        let path = path.as_ref();
+        let start = Instant::now();
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).mode(0o600).open(path) {
+                Ok(mut file) => {
+                    let lock = Lock { path: path.to_path_buf() };
+                    file.write_all(format!("{}\n", process::id()).as_bytes())?;
+                    return Ok(lock);
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(err) => return Err(err)
+            }
+
+            if is_stale(path) {
+                break_stale(path)?;
+                continue;
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} is held by another process", path.display())
+                ));
+            }
+
+            thread::sleep(RETRY_DELAY);
+        }
+    }
+
+    /// Path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Removes the stale lock at `path`.
+///
+/// Another waiter may have broken it and taken the lock since it was found stale,
+/// so the file is first moved out of the way, which only one process can do, and
+/// checked again. A live lock moved by mistake is put back.
+fn break_stale(path: &Path) -> io::Result<()> {
+    let mut moved = path.as_os_str().to_owned();
+    moved.push(format!(".stale.{}", process::id()));
+    let moved = PathBuf::from(moved);
+
+    match fs::rename(path, &moved) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err)
+    }
+
+    let result = if is_stale(&moved) {
+        Ok(())
+    } else {
+        match fs::hard_link(&moved, path) {
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} was taken while another lock was being restored", path.display())
+            )),
+            result => result
+        }
+    };
+    let _ = fs::remove_file(&moved);
+    result
+}
+
+/// Whether the lock file at `path` was left behind by a holder that is gone.
+fn is_stale(path: &Path) -> bool {
+    let mut data = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut data)).is_err() {
+        return false;
+    }
+
+    match data.trim().parse::<usize>() {
+        Ok(pid) => match syscall::kill(pid, 0) {
+            Err(err) => err.errno == syscall::error::ESRCH,
+            Ok(_) => false
+        },
+        // Still being written by its holder, unless that was long ago
+        Err(_) => fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| SystemTime::now().duration_since(modified).unwrap_or_default() > STALE_AGE)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_LOCK: AtomicUsize = AtomicUsize::new(0);
+
+    /// A throwaway lock file path, in a directory of its own.
+    fn lock_path() -> PathBuf {
+        let dir = env::temp_dir().join(format!("userutils-lock-{}-{}", process::id(), NEXT_LOCK.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("passwd.lock")
+    }
+
+    /// The PID of a process that already exited.
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    fn contents(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn locks_are_released_on_drop() {
+        let path = lock_path();
+        {
+            let lock = Lock::acquire(&path, Duration::from_secs(0)).unwrap();
+            assert_eq!(lock.path(), path.as_path());
+            assert_eq!(contents(&path), format!("{}\n", process::id()));
+        }
+        assert!(! path.exists());
+    }
+
+    #[test]
+    fn locks_of_dead_holders_are_broken() {
+        let path = lock_path();
+        fs::write(&path, format!("{}\n", dead_pid())).unwrap();
+        assert!(is_stale(&path));
+
+        let _lock = Lock::acquire(&path, Duration::from_secs(0)).unwrap();
+        assert_eq!(contents(&path), format!("{}\n", process::id()));
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn locks_of_live_holders_are_kept() {
+        let path = lock_path();
+        let holder = format!("{}\n", process::id());
+        fs::write(&path, &holder).unwrap();
+
+        // However old they are
+        File::open(&path).unwrap().set_modified(SystemTime::now() - STALE_AGE * 2).unwrap();
+        assert!(! is_stale(&path));
+
+        let err = Lock::acquire(&path, Duration::from_millis(300)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(contents(&path), holder);
+    }
+
+    #[test]
+    fn live_locks_broken_by_mistake_are_restored() {
+        let path = lock_path();
+        let holder = format!("{}\n", process::id());
+        fs::write(&path, &holder).unwrap();
+
+        break_stale(&path).unwrap();
+        assert_eq!(contents(&path), holder);
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+        // Already broken by someone else
+        let path = lock_path();
+        break_stale(&path).unwrap();
+        assert!(! path.exists());
+    }
+
+    #[test]
+    fn locks_without_a_pid_are_stale_once_old() {
+        let path = lock_path();
+        fs::write(&path, "").unwrap();
+        assert!(! is_stale(&path));
+        assert_eq!(Lock::acquire(&path, Duration::from_millis(300)).unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        File::open(&path).unwrap().set_modified(SystemTime::now() - STALE_AGE - Duration::from_secs(60)).unwrap();
+        assert!(is_stale(&path));
+        let _lock = Lock::acquire(&path, Duration::from_secs(0)).unwrap();
+        assert_eq!(contents(&path), format!("{}\n", process::id()));
+    }
+
+    #[test]
+    fn missing_locks_are_not_stale() {
+        assert!(! is_stale(&lock_path()));
+    }
+}
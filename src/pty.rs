@@ -0,0 +1,162 @@
+//! Pseudo terminals, for running a process on a terminal of its own while
+//! relaying it to the caller's.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process::{Child, Command, ExitStatus, Stdio};
+
+use redox_termios;
+use syscall;
+
+use iolog::Stream;
+
+/// Opens a new pseudo terminal of `columns` by `lines`, returning the file
+/// descriptor of its master side and the path of its slave side.
+pub fn getpty(columns: u32, lines: u32) -> io::Result<(usize, String)> {
+    let master = syscall::open("pty:", syscall::O_CLOEXEC | syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK)
+        .map_err(from_syscall)?;
+
+    if let Ok(winsize_fd) = syscall::dup(master, b"winsize") {
+        let _ = syscall::write(winsize_fd, &redox_termios::Winsize {
+            ws_row: lines as u16,
+            ws_col: columns as u16
+        });
+        let _ = syscall::close(winsize_fd);
+    }
+
+    let mut buf: [u8; 4096] = [0; 4096];
+    let count = syscall::fpath(master, &mut buf).map_err(from_syscall)?;
+    Ok((master, String::from_utf8_lossy(&buf[..count]).into_owned()))
+}
+
+/// Opens the terminal at `path` for `relay`.
+pub fn open_tty(path: &str) -> io::Result<usize> {
+    syscall::open(path, syscall::O_CLOEXEC | syscall::O_RDWR | syscall::O_NONBLOCK).map_err(from_syscall)
+}
+
+/// Spawns `command` with its standard streams on the slave side `pty`.
+pub fn spawn_on(mut command: Command, pty: &str) -> io::Result<Child> {
+    let stdin = syscall::open(pty, syscall::O_CLOEXEC | syscall::O_RDONLY).map_err(from_syscall)?;
+    let stdout = syscall::open(pty, syscall::O_CLOEXEC | syscall::O_WRONLY).map_err(from_syscall)?;
+    let stderr = syscall::open(pty, syscall::O_CLOEXEC | syscall::O_WRONLY).map_err(from_syscall)?;
+
+    // The slave side is closed in this process once `command` is dropped
+    unsafe {
+        command
+            .stdin(Stdio::from_raw_fd(stdin as RawFd))
+            .stdout(Stdio::from_raw_fd(stdout as RawFd))
+            .stderr(Stdio::from_raw_fd(stderr as RawFd));
+    }
+    command.env("TTY", pty);
+    command.spawn()
+}
+
+/// Relays the caller's terminal `tty` and the master side `master` of the pseudo
+/// terminal `child` runs on, until `child` exits or hangs up the terminal.
+///
+/// Both have to be opened non blocking. What is typed and what is displayed is
+/// handed to `record` along the way.
+pub fn relay<F: FnMut(Stream, &[u8])>(tty: usize, master: usize, child: &mut Child, mut record: F) -> io::Result<ExitStatus> {
+    let mut event_file = File::open("event:")?;
+    syscall::fevent(tty, syscall::EVENT_READ).map_err(from_syscall)?;
+    syscall::fevent(master, syscall::EVENT_READ).map_err(from_syscall)?;
+
+    // Relays what is available on `event_id`, returning how much was read, or
+    // `None` once the child hung up its side
+    let mut handle_event = |event_id: usize| -> io::Result<Option<usize>> {
+        let mut packet = [0; 4096];
+        if event_id == tty {
+            let count = read(tty, &mut packet)?.unwrap_or(0);
+            if count > 0 {
+                record(Stream::Input, &packet[..count]);
+                write_all(master, &packet[..count])?;
+            }
+            Ok(Some(count))
+        } else if event_id == master {
+            // The first byte of a packet holds flags, bit 0 asking for a flush
+            let count = match read(master, &mut packet)? {
+                Some(0) => return Ok(None),
+                Some(count) => count,
+                None => 0
+            };
+            if count > 0 {
+                record(Stream::Output, &packet[1..count]);
+                write_all(tty, &packet[1..count])?;
+                if packet[0] & 1 == 1 {
+                    let _ = syscall::fsync(tty);
+                }
+            }
+            Ok(Some(count))
+        } else {
+            Ok(Some(0))
+        }
+    };
+
+    handle_event(tty)?;
+    handle_event(master)?;
+
+    loop {
+        let mut sys_event = syscall::Event::default();
+        match event_file.read(&mut sys_event) {
+            Ok(_) => match handle_event(sys_event.id)? {
+                // Like getty, an event with nothing to read is the child hanging up
+                Some(0) if sys_event.id == master && sys_event.data == 0 => break,
+                None => break,
+                Some(_) => ()
+            },
+            // A signal handler ran, the child may have exited meanwhile
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
+        }
+
+        if let Some(status) = child.try_wait()? {
+            drain(&mut handle_event, master)?;
+            return Ok(status);
+        }
+    }
+
+    // What the child wrote right before hanging up may still be pending
+    drain(&mut handle_event, master)?;
+    loop {
+        match child.wait() {
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            result => return result
+        }
+    }
+}
+
+/// Relays what is left on `master` with `handle_event`.
+fn drain<F: FnMut(usize) -> io::Result<Option<usize>>>(handle_event: &mut F, master: usize) -> io::Result<()> {
+    while let Some(count) = handle_event(master)? {
+        if count == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads from a non blocking descriptor, `None` when nothing is available and
+/// `Some(0)` at the end of file.
+fn read(fd: usize, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    match syscall::read(fd, buf) {
+        Ok(count) => Ok(Some(count)),
+        Err(ref err) if err.errno == syscall::error::EAGAIN => Ok(None),
+        Err(err) => Err(from_syscall(err))
+    }
+}
+
+fn write_all(fd: usize, mut buf: &[u8]) -> io::Result<()> {
+    while ! buf.is_empty() {
+        match syscall::write(fd, buf) {
+            Ok(count) => buf = &buf[count..],
+            Err(ref err) if err.errno == syscall::error::EAGAIN => (),
+            Err(err) => return Err(from_syscall(err))
+        }
+    }
+    Ok(())
+}
+
+fn from_syscall(err: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(err.errno)
+}
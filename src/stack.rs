@@ -0,0 +1,566 @@
+//! Pluggable authentication stacks.
+//!
+//! Each service (`login`, `su`, `sudo`, `passwd`) reads its policy from
+//! `/etc/auth.d/<service>`. Every non empty line that is not a `#` comment has the
+//! form:
+//!
+//! ```text
+//! phase control module [args...]
+//! ```
+//!
+//! where `phase` is one of `auth`, `account` or `session`, `control` one of
+//! `required`, `requisite`, `sufficient` or `optional` and `module` one of:
+//!
+//! - `unix`: checks the user's password. Accepts `nullok` to let users without a
+//!   password through, `attempts=N` and `delay=SECONDS`. Legacy or weak hashes are
+//!   upgraded after a successful check when the caller allows it, see
+//!   `AuthContext::upgrade`.
+//! - `deny`: always fails.
+//! - `permit`: always succeeds.
+//! - `rootok`: succeeds only when the invoking user is root.
+//! - `nologin`: fails for everyone but root while `/etc/nologin` exists.
+//! - `securetty`: succeeds only on the terminals listed in `/etc/securetty`, or in
+//!   the file given as `file=PATH`.
+//!
+//! Modules of a phase run in order. A `required` failure fails the phase once all
+//! its modules ran, a `requisite` failure fails it right away, a `sufficient`
+//! success ends the phase successfully unless a `required` module failed before
+//! and `optional` results are only used when nothing else decided. A phase where
+//! no module succeeded fails. When the file of a service does not exist a built-in
+//! stack matching the historical behaviour of the utility is used.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use auth::{AuthResult, FAIL_DELAY, MAX_ATTEMPTS, PasswordSource, prompt_password};
+use current_tty;
+use db::{AccountDb, User};
+use hash::{self, HashParams};
+
+/// Directory holding the stack of every service.
+pub const AUTH_DIR: &'static str = "/etc/auth.d";
+/// While this file exists only root may log in.
+pub const NOLOGIN_FILE: &'static str = "/etc/nologin";
+/// Default terminal list of the `securetty` module.
+pub const SECURETTY_FILE: &'static str = "/etc/securetty";
+
+/// Stage of a session a stack entry applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Proving the user's identity.
+    Auth,
+    /// Checking whether the account may be used right now.
+    Account,
+    /// Right before the session is started.
+    Session,
+}
+
+impl FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Phase, String> {
+        match s {
+            "auth" => Ok(Phase::Auth),
+            "account" => Ok(Phase::Account),
+            "session" => Ok(Phase::Session),
+            _ => Err(format!("unknown phase '{}'", s))
+        }
+    }
+}
+
+/// How the result of a stack entry affects its phase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    /// Must succeed, the remaining entries still run.
+    Required,
+    /// Must succeed, the phase fails right away otherwise.
+    Requisite,
+    /// Ends the phase successfully when it succeeds.
+    Sufficient,
+    /// Only matters when no other entry decided.
+    Optional,
+}
+
+impl FromStr for Control {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Control, String> {
+        match s {
+            "required" => Ok(Control::Required),
+            "requisite" => Ok(Control::Requisite),
+            "sufficient" => Ok(Control::Sufficient),
+            "optional" => Ok(Control::Optional),
+            _ => Err(format!("unknown control '{}'", s))
+        }
+    }
+}
+
+/// A built-in module along with its arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Module {
+    /// Password check against the account database.
+    Unix { nullok: bool, attempts: u32, delay: Duration },
+    /// Always fails.
+    Deny,
+    /// Always succeeds.
+    Permit,
+    /// Succeeds when the invoking user is root.
+    RootOk,
+    /// Fails for non root users while `NOLOGIN_FILE` exists.
+    NoLogin,
+    /// Succeeds on the terminals listed in `file`.
+    SecureTty { file: String },
+}
+
+impl Module {
+    /// Builds the module called `name` out of its arguments.
+    pub fn new(name: &str, args: &[&str]) -> Result<Module, String> {
+        let module = match name {
+            "unix" => {
+                let mut nullok = false;
+                let mut attempts = MAX_ATTEMPTS;
+                let mut delay = FAIL_DELAY;
+                for arg in args {
+                    if *arg == "nullok" {
+                        nullok = true;
+                    } else if arg.starts_with("attempts=") {
+                        attempts = arg["attempts=".len()..].parse()
+                            .map_err(|_| format!("invalid argument '{}'", arg))?;
+                    } else if arg.starts_with("delay=") {
+                        delay = Duration::from_secs(arg["delay=".len()..].parse()
+                            .map_err(|_| format!("invalid argument '{}'", arg))?);
+                    } else {
+                        return Err(format!("unknown argument '{}' for module unix", arg));
+                    }
+                }
+                return Ok(Module::Unix { nullok: nullok, attempts: attempts, delay: delay });
+            },
+            "securetty" => {
+                let mut file = SECURETTY_FILE.to_string();
+                for arg in args {
+                    if arg.starts_with("file=") {
+                        file = arg["file=".len()..].to_string();
+                    } else {
+                        return Err(format!("unknown argument '{}' for module securetty", arg));
+                    }
+                }
+                return Ok(Module::SecureTty { file: file });
+            },
+            "deny" => Module::Deny,
+            "permit" => Module::Permit,
+            "rootok" => Module::RootOk,
+            "nologin" => Module::NoLogin,
+            _ => return Err(format!("unknown module '{}'", name))
+        };
+
+        match args.first() {
+            Some(arg) => Err(format!("unknown argument '{}' for module {}", arg, name)),
+            None => Ok(module)
+        }
+    }
+
+    /// Runs the module for `phase`.
+    pub fn run(&self, phase: Phase, ctx: &AuthContext) -> AuthResult {
+        match *self {
+            Module::Unix { nullok, attempts, delay } => {
+                if phase != Phase::Auth {
+                    return AuthResult::Success;
+                }
+
+                match prompt_password(ctx.user, &ctx.source, &ctx.prompt, attempts, delay) {
+                    Ok(password) => {
+                        if let Some(db) = ctx.upgrade {
+                            upgrade_hash(db, ctx.user, &password);
+                        }
+                        AuthResult::Success
+                    },
+                    Err(AuthResult::NoPassword) if nullok => AuthResult::Success,
+                    Err(result) => result
+                }
+            },
+            Module::Deny => AuthResult::Denied,
+            Module::Permit => AuthResult::Success,
+            Module::RootOk => if ctx.caller == 0 {
+                AuthResult::Success
+            } else {
+                AuthResult::Denied
+            },
+            Module::NoLogin => {
+                if ctx.user.uid != 0 {
+                    if let Ok(mut file) = File::open(NOLOGIN_FILE) {
+                        let mut msg = String::new();
+                        let _ = file.read_to_string(&mut msg);
+                        eprint!("{}", msg);
+                        return AuthResult::Denied;
+                    }
+                }
+                AuthResult::Success
+            },
+            Module::SecureTty { ref file } => {
+                let tty = match current_tty() {
+                    Some(tty) => tty,
+                    None => return AuthResult::Denied
+                };
+
+                let mut ttys = String::new();
+                if File::open(file).and_then(|mut file| file.read_to_string(&mut ttys)).is_err() {
+                    return AuthResult::Denied;
+                }
+
+                if ttys.lines().map(|line| line.trim()).any(|line| line == tty) {
+                    AuthResult::Success
+                } else {
+                    AuthResult::Denied
+                }
+            }
+        }
+    }
+}
+
+/// Rehashes `password` with the configured parameters if `user`'s hash is outdated.
+///
+/// This is best effort, the user is already authenticated when it runs.
+fn upgrade_hash(db: &dyn AccountDb, user: &User, password: &str) {
+    let params = match HashParams::load() {
+        Ok(params) => params,
+        Err(_) => return
+    };
+
+    if ! hash::needs_rehash(&user.hash, &params) {
+        return;
+    }
+
+    let new_hash = match hash::hash_password(password, &params) {
+        Ok(new_hash) => new_hash,
+        Err(_) => return
+    };
+
+    if let Ok(_lock) = db.lock() {
+        if let Some(mut user) = db.user_by_name(&user.user) {
+            user.hash = new_hash;
+            let _ = db.update_user(&user);
+        }
+    }
+}
+
+/// One line of a stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub phase: Phase,
+    pub control: Control,
+    pub module: Module,
+}
+
+/// What a stack is being run for.
+pub struct AuthContext<'a> {
+    /// The account being authenticated.
+    pub user: &'a User,
+    /// Real uid of the invoking process.
+    pub caller: u32,
+    /// Prompt used when asking for a password.
+    pub prompt: String,
+    /// Where the password is read from.
+    pub source: PasswordSource,
+    /// Database to store the user's hash in when it gets upgraded, `None` to never
+    /// upgrade hashes.
+    pub upgrade: Option<&'a dyn AccountDb>,
+}
+
+/// The authentication policy of a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthStack {
+    pub service: String,
+    pub entries: Vec<Entry>,
+}
+
+impl AuthStack {
+    /// Loads the stack of `service` from `AUTH_DIR`.
+    ///
+    /// Falls back to `AuthStack::builtin` when the service has no file.
+    pub fn load(service: &str) -> io::Result<AuthStack> {
+        AuthStack::load_from(Path::new(AUTH_DIR), service)
+    }
+
+    /// Loads the stack of `service` from the given directory.
+    pub fn load_from(dir: &Path, service: &str) -> io::Result<AuthStack> {
+        let path = dir.join(service);
+        let mut data = String::new();
+        match File::open(&path) {
+            Ok(mut file) => { file.read_to_string(&mut data)?; },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(AuthStack::builtin(service)),
+            Err(err) => return Err(err)
+        }
+
+        AuthStack::parse(service, &data).map_err(|(line, msg)| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}:{}: {}", path.display(), line, msg)
+        ))
+    }
+
+    /// Parses a stack, errors come with the line number they were found on.
+    pub fn parse(service: &str, data: &str) -> Result<AuthStack, (usize, String)> {
+        let mut entries = Vec::new();
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words = line.split_whitespace().collect::<Vec<&str>>();
+            if words.len() < 3 {
+                return Err((i + 1, "expected 'phase control module [args...]'".to_string()));
+            }
+
+            entries.push(Entry {
+                phase: words[0].parse().map_err(|err| (i + 1, err))?,
+                control: words[1].parse().map_err(|err| (i + 1, err))?,
+                module: Module::new(words[2], &words[3..]).map_err(|err| (i + 1, err))?,
+            });
+        }
+
+        Ok(AuthStack {
+            service: service.to_string(),
+            entries: entries,
+        })
+    }
+
+    /// The stack used for `service` when it has no file.
+    pub fn builtin(service: &str) -> AuthStack {
+        let data = match service {
+            "login" => "auth required unix nullok\naccount required nologin\n",
+            "su" | "sudo" | "passwd" => "auth sufficient rootok\nauth required unix nullok\n",
+            _ => "auth required deny\n"
+        };
+
+        AuthStack::parse(service, data).expect("built-in stack is valid")
+    }
+
+    /// Runs the entries of `phase`.
+    pub fn run(&self, phase: Phase, ctx: &AuthContext) -> AuthResult {
+        let mut failure = None;
+        let mut passed = false;
+
+        for entry in self.entries.iter().filter(|entry| entry.phase == phase) {
+            let result = entry.module.run(phase, ctx);
+            let success = result == AuthResult::Success;
+
+            match entry.control {
+                Control::Required => if success {
+                    passed = true;
+                } else if failure.is_none() {
+                    failure = Some(result);
+                },
+                Control::Requisite => if success {
+                    passed = true;
+                } else {
+                    return failure.unwrap_or(result);
+                },
+                Control::Sufficient => if success && failure.is_none() {
+                    return AuthResult::Success;
+                },
+                Control::Optional => if success {
+                    passed = true;
+                }
+            }
+        }
+
+        match failure {
+            Some(result) => result,
+            None if passed => AuthResult::Success,
+            None => AuthResult::Denied
+        }
+    }
+
+    /// Runs the `auth` phase and, when it succeeds, the `account` one.
+    ///
+    /// An `account` phase without entries succeeds.
+    pub fn authenticate(&self, ctx: &AuthContext) -> AuthResult {
+        match self.run(Phase::Auth, ctx) {
+            AuthResult::Success => self.check_account(ctx),
+            result => result
+        }
+    }
+
+    /// Runs the `account` phase alone, for callers that were told by their own
+    /// policy not to ask for credentials.
+    ///
+    /// An `account` phase without entries succeeds.
+    pub fn check_account(&self, ctx: &AuthContext) -> AuthResult {
+        self.run_optional(Phase::Account, ctx)
+    }
+
+    /// Runs the `session` phase, to be called right before starting the session.
+    ///
+    /// A `session` phase without entries succeeds.
+    pub fn open_session(&self, ctx: &AuthContext) -> AuthResult {
+        self.run_optional(Phase::Session, ctx)
+    }
+
+    fn run_optional(&self, phase: Phase, ctx: &AuthContext) -> AuthResult {
+        if self.entries.iter().any(|entry| entry.phase == phase) {
+            self.run(phase, ctx)
+        } else {
+            AuthResult::Success
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn stack(data: &str) -> AuthStack {
+        AuthStack::parse("test", data).unwrap()
+    }
+
+    fn user(hash: &str) -> User {
+        User {
+            user: "alice".to_string(),
+            hash: hash.to_string(),
+            uid: 1000,
+            gid: 1000,
+            name: "Alice".to_string(),
+            home: "/home/alice".to_string(),
+            shell: "/bin/ion".to_string(),
+        }
+    }
+
+    /// Runs the `auth` phase and the `account` one for `user`, invoked by `caller`.
+    /// Passwords can not be read, so `unix` aborts unless it lets the user through.
+    fn authenticate(stack: &AuthStack, user: &User, caller: u32) -> AuthResult {
+        stack.authenticate(&AuthContext {
+            user: user,
+            caller: caller,
+            prompt: String::new(),
+            source: PasswordSource::NonInteractive,
+            upgrade: None,
+        })
+    }
+
+    #[test]
+    fn entries() {
+        let stack = stack("# comment\n\nauth  sufficient rootok\n  auth required unix nullok attempts=5 delay=1\naccount requisite nologin\nsession optional permit\n");
+        assert_eq!(stack.entries, vec![
+            Entry { phase: Phase::Auth, control: Control::Sufficient, module: Module::RootOk },
+            Entry {
+                phase: Phase::Auth,
+                control: Control::Required,
+                module: Module::Unix { nullok: true, attempts: 5, delay: Duration::from_secs(1) },
+            },
+            Entry { phase: Phase::Account, control: Control::Requisite, module: Module::NoLogin },
+            Entry { phase: Phase::Session, control: Control::Optional, module: Module::Permit },
+        ]);
+        assert_eq!(AuthStack::parse("test", "auth required securetty file=/etc/ttys\n").unwrap().entries[0].module,
+                   Module::SecureTty { file: "/etc/ttys".to_string() });
+    }
+
+    #[test]
+    fn errors_come_with_their_line() {
+        assert_eq!(AuthStack::parse("test", "auth required\n").unwrap_err().0, 1);
+        assert_eq!(AuthStack::parse("test", "# comment\nlogin required permit\n").unwrap_err(),
+                   (2, "unknown phase 'login'".to_string()));
+        assert_eq!(AuthStack::parse("test", "auth mandatory permit\n").unwrap_err().1, "unknown control 'mandatory'");
+        assert_eq!(AuthStack::parse("test", "auth required ldap\n").unwrap_err().1, "unknown module 'ldap'");
+        assert!(AuthStack::parse("test", "auth required unix attempts=many\n").is_err());
+        assert!(AuthStack::parse("test", "auth required unix nulok\n").is_err());
+        assert!(AuthStack::parse("test", "auth required permit always\n").is_err());
+    }
+
+    #[test]
+    fn builtin_and_loaded_stacks() {
+        let dir = env::temp_dir().join(format!("userutils-stack-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(AuthStack::load_from(&dir, "sudo").unwrap(), AuthStack::builtin("sudo"));
+        assert_eq!(AuthStack::builtin("unknown").entries[0].module, Module::Deny);
+
+        fs::write(dir.join("sudo"), "auth required permit\n").unwrap();
+        assert_eq!(AuthStack::load_from(&dir, "sudo").unwrap().entries[0].module, Module::Permit);
+
+        fs::write(dir.join("sudo"), "auth required permit\nauth\n").unwrap();
+        let err = AuthStack::load_from(&dir, "sudo").unwrap_err().to_string();
+        assert!(err.starts_with(&format!("{}:2: ", dir.join("sudo").display())), "{}", err);
+    }
+
+    #[test]
+    fn required_entries_must_all_succeed() {
+        let alice = user("");
+        let stack = stack("auth required permit\nauth required rootok\n");
+        assert_eq!(authenticate(&stack, &alice, 0), AuthResult::Success);
+        assert_eq!(authenticate(&stack, &alice, 1000), AuthResult::Denied);
+
+        // Even a sufficient success after them
+        let stack = self::stack("auth required rootok\nauth sufficient permit\n");
+        assert_eq!(authenticate(&stack, &alice, 1000), AuthResult::Denied);
+    }
+
+    #[test]
+    fn requisite_entries_fail_right_away() {
+        let alice = user("$argon2i$hash");
+        let stack = stack("auth requisite rootok\nauth sufficient permit\n");
+        assert_eq!(authenticate(&stack, &alice, 1000), AuthResult::Denied);
+        assert_eq!(authenticate(&stack, &alice, 0), AuthResult::Success);
+
+        // The first failure is the one reported
+        let stack = self::stack("auth required deny\nauth requisite unix\n");
+        assert_eq!(authenticate(&stack, &alice, 1000), AuthResult::Denied);
+        let stack = self::stack("auth requisite unix\nauth required deny\n");
+        assert_eq!(authenticate(&stack, &alice, 1000), AuthResult::Aborted);
+    }
+
+    #[test]
+    fn sufficient_entries_end_the_phase() {
+        let alice = user("$argon2i$hash");
+
+        // Root gets through the built-in stack without being asked for a password
+        let stack = AuthStack::builtin("sudo");
+        assert_eq!(authenticate(&stack, &alice, 0), AuthResult::Success);
+        assert_eq!(authenticate(&stack, &alice, 1000), AuthResult::Aborted);
+
+        let stack = self::stack("auth sufficient permit\nauth required deny\n");
+        assert_eq!(authenticate(&stack, &alice, 1000), AuthResult::Success);
+    }
+
+    #[test]
+    fn optional_entries_only_decide_alone() {
+        let alice = user("");
+        assert_eq!(authenticate(&stack("auth optional deny\nauth required permit\n"), &alice, 1000), AuthResult::Success);
+        assert_eq!(authenticate(&stack("auth optional permit\n"), &alice, 1000), AuthResult::Success);
+        assert_eq!(authenticate(&stack("auth optional deny\n"), &alice, 1000), AuthResult::Denied);
+        assert_eq!(authenticate(&stack("auth optional deny\nauth required deny\n"), &alice, 1000), AuthResult::Denied);
+    }
+
+    #[test]
+    fn phases() {
+        let alice = user("");
+
+        // Nothing in the auth phase lets nobody in, the others may be left out
+        assert_eq!(authenticate(&stack(""), &alice, 0), AuthResult::Denied);
+        assert_eq!(authenticate(&stack("auth required permit\n"), &alice, 1000), AuthResult::Success);
+        assert_eq!(authenticate(&stack("auth required permit\naccount required deny\n"), &alice, 1000), AuthResult::Denied);
+
+        let stack = stack("auth required deny\nsession required deny\n");
+        let ctx = AuthContext {
+            user: &alice,
+            caller: 1000,
+            prompt: String::new(),
+            source: PasswordSource::NonInteractive,
+            upgrade: None,
+        };
+        assert_eq!(stack.check_account(&ctx), AuthResult::Success);
+        assert_eq!(stack.open_session(&ctx), AuthResult::Denied);
+    }
+
+    #[test]
+    fn users_without_a_password() {
+        let alice = user("");
+        assert_eq!(authenticate(&stack("auth required unix nullok\n"), &alice, 1000), AuthResult::Success);
+        assert_eq!(authenticate(&stack("auth required unix\n"), &alice, 1000), AuthResult::NoPassword);
+    }
+}
@@ -0,0 +1,422 @@
+//! Evaluation of requests against a parsed policy.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::{Args, Cmnd, CmndMember, Item, Member, Rule, Runas, SUDOEDIT, Sudoers, Tags};
+
+/// Aliases referring to each other deeper than this are not followed.
+const MAX_ALIAS_DEPTH: usize = 32;
+
+/// A user as seen by the policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Identity<'a> {
+    /// Login name.
+    pub name: &'a str,
+    /// Names of the groups the user is a member of, including its primary group.
+    pub groups: &'a [String],
+}
+
+/// What the invoking user is asking for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    /// The invoking user.
+    pub user: Identity<'a>,
+    /// Name of the host, see `hostname`.
+    pub host: &'a str,
+    /// The user to run the command as.
+    pub runas_user: Identity<'a>,
+    /// The group to run the command as, `None` for the target user's own groups.
+    pub runas_group: Option<&'a str>,
+    /// Absolute path of the command.
+    pub command: &'a str,
+    /// Arguments of the command.
+    pub args: &'a [String],
+}
+
+/// Outcome of a policy check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The command may run, with the tags of the rule that allowed it.
+    Allow(Tags),
+    /// No rule allows the command, or the last matching one forbids it.
+    Deny,
+}
+
+impl Sudoers {
+    /// Decides whether `request` is allowed. The last matching command of the
+    /// policy wins.
+    pub fn check(&self, request: &Request) -> Decision {
+        let mut decision = Decision::Deny;
+
+        for rule in self.rules.iter() {
+            if ! self.user_matches(&rule.users, &request.user)
+                || ! self.host_matches(&rule.hosts, request.host) {
+                continue;
+            }
+
+            for spec in rule.specs.iter() {
+                if ! self.runas_matches(&spec.runas, request) {
+                    continue;
+                }
+
+                match self.cmnd_matches(&spec.cmnd, request.command, request.args, 0) {
+                    Some(true) => decision = Decision::Allow(spec.tags),
+                    Some(false) => decision = Decision::Deny,
+                    None => ()
+                }
+            }
+        }
+
+        decision
+    }
+
+    /// The rules that apply to `user` on `host`.
+    pub fn rules_for(&self, user: &Identity, host: &str) -> Vec<&Rule> {
+        self.rules.iter()
+            .filter(|rule| self.user_matches(&rule.users, user) && self.host_matches(&rule.hosts, host))
+            .collect()
+    }
+
+    /// Replaces `Cmnd_Alias` references by the commands they stand for, for
+    /// display.
+    pub fn expand_cmnd(&self, cmnd: &CmndMember) -> Vec<CmndMember> {
+        self.expand_cmnd_at(cmnd, 0)
+    }
+
+    fn expand_cmnd_at(&self, cmnd: &CmndMember, depth: usize) -> Vec<CmndMember> {
+        let members = match (&cmnd.cmnd, depth < MAX_ALIAS_DEPTH) {
+            (&Cmnd::Alias(ref name), true) => match self.cmnd_aliases.get(name) {
+                Some(members) => members,
+                None => return vec![cmnd.clone()]
+            },
+            _ => return vec![cmnd.clone()]
+        };
+
+        members.iter()
+            .flat_map(|member| self.expand_cmnd_at(member, depth + 1))
+            .map(|member| CmndMember {
+                negated: member.negated != cmnd.negated,
+                cmnd: member.cmnd,
+            })
+            .collect()
+    }
+
+    /// Whether `user` is matched by `list`, using `User_Alias` definitions.
+    pub fn user_matches(&self, list: &[Member], user: &Identity) -> bool {
+        matches(list, &self.user_aliases, &|item| identity_matches(item, user), 0) == Some(true)
+    }
+
+    /// Whether `host` is matched by `list`, using `Host_Alias` definitions.
+    pub fn host_matches(&self, list: &[Member], host: &str) -> bool {
+        matches(list, &self.host_aliases, &|item| match *item {
+            Item::Name(ref name) => name == host,
+            _ => false
+        }, 0) == Some(true)
+    }
+
+    /// Whether the targets of `request` are allowed by `runas`.
+    pub fn runas_matches(&self, runas: &Runas, request: &Request) -> bool {
+        let user_ok = match runas.users {
+            Some(ref users) => matches(users, &self.runas_aliases, &|item| {
+                identity_matches(item, &request.runas_user)
+            }, 0) == Some(true),
+            // Only groups were given, the command runs as the invoking user
+            None if runas.groups.is_some() => request.runas_user.name == request.user.name,
+            None => request.runas_user.name == "root"
+        };
+
+        let group_ok = match (request.runas_group, runas.groups.as_ref()) {
+            (None, _) => true,
+            (Some(group), Some(groups)) => matches(groups, &self.runas_aliases, &|item| match *item {
+                Item::Name(ref name) => name == group,
+                _ => false
+            }, 0) == Some(true),
+            (Some(_), None) => false
+        };
+
+        user_ok && group_ok
+    }
+
+    /// Whether `command` with `args` is matched by `cmnd`, `Some(false)` when it
+    /// is matched by a negated entry.
+    fn cmnd_matches(&self, cmnd: &CmndMember, command: &str, args: &[String], depth: usize) -> Option<bool> {
+        let matched = match cmnd.cmnd {
+            Cmnd::All => Some(true),
+            Cmnd::Alias(ref name) => {
+                let members = match self.cmnd_aliases.get(name) {
+                    Some(members) if depth < MAX_ALIAS_DEPTH => members,
+                    _ => return None
+                };
+
+                let mut result = None;
+                for member in members.iter() {
+                    if let Some(value) = self.cmnd_matches(member, command, args, depth + 1) {
+                        result = Some(value);
+                    }
+                }
+                result
+            },
+            Cmnd::Path { ref path, args: ref allowed } => {
+                if path_matches(path, command) && args_match(allowed, args, command == SUDOEDIT) {
+                    Some(true)
+                } else {
+                    None
+                }
+            }
+        };
+
+        matched.map(|value| value != cmnd.negated)
+    }
+}
+
+/// Runs through `list`, the last matching member decides. `None` when nothing
+/// matched.
+fn matches(list: &[Member], aliases: &BTreeMap<String, Vec<Member>>, item_matches: &dyn Fn(&Item) -> bool, depth: usize) -> Option<bool> {
+    let mut result = None;
+
+    for member in list.iter() {
+        let matched = match member.item {
+            Item::All => Some(true),
+            Item::Alias(ref name) => match aliases.get(name) {
+                Some(members) if depth < MAX_ALIAS_DEPTH => matches(members, aliases, item_matches, depth + 1),
+                _ => None
+            },
+            ref item => if item_matches(item) { Some(true) } else { None }
+        };
+
+        if let Some(value) = matched {
+            result = Some(value != member.negated);
+        }
+    }
+
+    result
+}
+
+fn identity_matches(item: &Item, identity: &Identity) -> bool {
+    match *item {
+        Item::Name(ref name) => name == identity.name,
+        Item::Group(ref group) => identity.groups.iter().any(|name| name == group),
+        _ => false
+    }
+}
+
+/// Matches an absolute command path against a policy path, pattern or directory.
+fn path_matches(pattern: &str, command: &str) -> bool {
+    if pattern.ends_with('/') {
+        return match Path::new(command).parent() {
+            Some(parent) => Path::new(pattern) == parent,
+            None => false
+        };
+    }
+
+    glob(pattern.as_bytes(), command.as_bytes(), true)
+}
+
+/// Matches the arguments against what the policy allows.
+///
+/// The arguments of `sudoedit` are files, each must match one of the paths of the
+/// pattern on its own.
+fn args_match(allowed: &Args, args: &[String], files: bool) -> bool {
+    match *allowed {
+        Args::Any => true,
+        Args::Empty => args.is_empty(),
+        Args::Pattern(ref pattern) if files => args.iter().all(|file| {
+            pattern.split_whitespace().any(|allowed| glob(allowed.as_bytes(), file.as_bytes(), true))
+        }),
+        Args::Pattern(ref pattern) => glob(pattern.as_bytes(), args.join(" ").as_bytes(), false)
+    }
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters and
+/// `?` any single one, neither matching `/` when `path` is set.
+///
+/// On a mismatch only the last `*` is given one more character, earlier ones
+/// could not match anything it can not, so this runs in linear space and at
+/// worst in time proportional to the product of both lengths.
+pub fn glob(pattern: &[u8], text: &[u8], path: bool) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position in the pattern after the last `*` and in the text where it ends
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&b'*') => {
+                p += 1;
+                star = Some((p, t));
+                continue;
+            },
+            Some(&b'?') if ! (path && text[t] == b'/') => {
+                p += 1;
+                t += 1;
+                continue;
+            },
+            Some(&c) if c != b'?' && c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            },
+            _ => ()
+        }
+
+        match star {
+            Some((after, end)) if ! (path && text[end] == b'/') => {
+                p = after;
+                t = end + 1;
+                star = Some((after, t));
+            },
+            _ => return false
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &'static str = "\
+User_Alias   ADMINS = alice, %wheel
+Runas_Alias  SERVICES = www, db
+Host_Alias   SERVERS = build1, build2
+Cmnd_Alias   PKG = /usr/bin/pkg install *, /usr/bin/pkg update
+
+ADMINS   ALL = (ALL:ALL) ALL, !/bin/su
+bob      SERVERS = (SERVICES) NOPASSWD: /bin/ls, PASSWD: /bin/cat \"\"
+carol    ALL = (:staff) /bin/id, (root) LOG_OUTPUT: PKG, !/usr/bin/pkg install evil
+%ops     ALL = /sbin/, /usr/bin/sudo?edit /etc/*
+";
+
+    fn check(user: &str, groups: &[&str], host: &str, runas: &str, group: Option<&str>,
+             command: &str, args: &[&str]) -> Decision {
+        let policy = Sudoers::parse(POLICY).unwrap();
+        let groups = groups.iter().map(|group| group.to_string()).collect::<Vec<String>>();
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        let no_groups = Vec::new();
+        policy.check(&Request {
+            user: Identity { name: user, groups: &groups },
+            host: host,
+            runas_user: Identity { name: runas, groups: &no_groups },
+            runas_group: group,
+            command: command,
+            args: &args,
+        })
+    }
+
+    fn allowed(decision: Decision) -> bool {
+        decision != Decision::Deny
+    }
+
+    #[test]
+    fn user_aliases_match_names_and_groups() {
+        assert!(allowed(check("alice", &[], "h", "root", None, "/bin/ls", &[])));
+        assert!(allowed(check("dave", &["wheel"], "h", "root", None, "/bin/ls", &[])));
+        assert!(! allowed(check("dave", &["users"], "h", "root", None, "/bin/ls", &[])));
+    }
+
+    #[test]
+    fn negated_command_after_all_denies() {
+        assert!(! allowed(check("alice", &[], "h", "root", None, "/bin/su", &[])));
+        assert!(! allowed(check("carol", &[], "h", "root", None, "/usr/bin/pkg", &["install", "evil"])));
+        assert!(allowed(check("carol", &[], "h", "root", None, "/usr/bin/pkg", &["install", "good"])));
+    }
+
+    #[test]
+    fn host_aliases() {
+        assert!(allowed(check("bob", &[], "build2", "www", None, "/bin/ls", &[])));
+        assert!(! allowed(check("bob", &[], "laptop", "www", None, "/bin/ls", &[])));
+    }
+
+    #[test]
+    fn runas_lists() {
+        assert!(allowed(check("bob", &[], "build1", "db", None, "/bin/ls", &[])));
+        assert!(! allowed(check("bob", &[], "build1", "root", None, "/bin/ls", &[])));
+        assert!(! allowed(check("bob", &[], "build1", "www", Some("wheel"), "/bin/ls", &[])));
+        // A group only run-as list runs the command as the invoking user
+        assert!(allowed(check("carol", &[], "h", "carol", Some("staff"), "/bin/id", &[])));
+        assert!(! allowed(check("carol", &[], "h", "root", Some("staff"), "/bin/id", &[])));
+        assert!(! allowed(check("carol", &[], "h", "carol", Some("wheel"), "/bin/id", &[])));
+        // Without a run-as list only root is allowed
+        assert!(allowed(check("dave", &["ops"], "h", "root", None, "/sbin/reboot", &[])));
+        assert!(! allowed(check("dave", &["ops"], "h", "www", None, "/sbin/reboot", &[])));
+    }
+
+    #[test]
+    fn argument_patterns() {
+        assert!(allowed(check("carol", &[], "h", "root", None, "/usr/bin/pkg", &["update"])));
+        assert!(! allowed(check("carol", &[], "h", "root", None, "/usr/bin/pkg", &["update", "-f"])));
+        assert!(! allowed(check("carol", &[], "h", "root", None, "/usr/bin/pkg", &["remove", "x"])));
+    }
+
+    #[test]
+    fn empty_arguments() {
+        assert!(allowed(check("bob", &[], "build1", "www", None, "/bin/cat", &[])));
+        assert!(! allowed(check("bob", &[], "build1", "www", None, "/bin/cat", &["/etc/shadow"])));
+    }
+
+    #[test]
+    fn directories_and_path_patterns() {
+        assert!(allowed(check("dave", &["ops"], "h", "root", None, "/sbin/reboot", &["-f"])));
+        assert!(! allowed(check("dave", &["ops"], "h", "root", None, "/sbin/x/reboot", &[])));
+        assert!(allowed(check("dave", &["ops"], "h", "root", None, "/usr/bin/sudo-edit", &["/etc/motd"])));
+        assert!(! allowed(check("dave", &["ops"], "h", "root", None, "/usr/bin/sudo/edit", &["/etc/motd"])));
+    }
+
+    #[test]
+    fn tags_carry_over() {
+        match check("bob", &[], "build1", "www", None, "/bin/ls", &[]) {
+            Decision::Allow(tags) => assert!(! tags.passwd),
+            Decision::Deny => panic!("denied")
+        }
+        match check("bob", &[], "build1", "www", None, "/bin/cat", &[]) {
+            Decision::Allow(tags) => assert!(tags.passwd),
+            Decision::Deny => panic!("denied")
+        }
+        match check("carol", &[], "h", "root", None, "/usr/bin/pkg", &["update"]) {
+            Decision::Allow(tags) => assert!(tags.log_output && ! tags.log_input),
+            Decision::Deny => panic!("denied")
+        }
+    }
+
+    #[test]
+    fn sudoedit_matches_each_file() {
+        let policy = Sudoers::parse("alice ALL = sudoedit /etc/*\n").unwrap();
+        let no_groups = Vec::new();
+        let check = |files: &[&str]| {
+            let args = files.iter().map(|file| file.to_string()).collect::<Vec<String>>();
+            policy.check(&Request {
+                user: Identity { name: "alice", groups: &no_groups },
+                host: "h",
+                runas_user: Identity { name: "root", groups: &no_groups },
+                runas_group: None,
+                command: SUDOEDIT,
+                args: &args,
+            }) != Decision::Deny
+        };
+        assert!(check(&["/etc/motd", "/etc/issue"]));
+        assert!(! check(&["/etc/motd", "/root/x"]));
+        assert!(! check(&["/etc/ssh/sshd_config"]));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob(b"/bin/*", b"/bin/ls", true));
+        assert!(! glob(b"/bin/*", b"/bin/x/ls", true));
+        assert!(glob(b"a*b", b"a/x/b", false));
+        assert!(glob(b"?at", b"cat", true));
+        assert!(! glob(b"?at", b"/at", true));
+        assert!(glob(b"*.conf", b".conf", true));
+        assert!(glob(b"a*b*c", b"aXbYbZc", false));
+        assert!(! glob(b"a*b*c", b"aXbYbZ", false));
+        assert!(glob(b"**", b"", false));
+        assert!(! glob(b"", b"x", false));
+    }
+
+    #[test]
+    fn glob_does_not_backtrack_exponentially() {
+        let pattern = "*a".repeat(30) + "b";
+        let text = "a".repeat(200);
+        assert!(! glob(pattern.as_bytes(), text.as_bytes(), false));
+    }
+}
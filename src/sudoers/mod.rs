@@ -0,0 +1,413 @@
+//! The policy deciding who may run what through `sudo`.
+//!
+//! The policy is read from `/etc/sudoers`, which follows a subset of the classic
+//! sudoers syntax. Empty lines and lines starting with `#` are ignored and a line
+//! ending with `\` continues on the next one. Aliases name lists that can be used
+//! wherever a list of the same kind is expected, once defined:
+//!
+//! ```text
+//! User_Alias   BUILDERS = alice, bob, %build
+//! Runas_Alias  SERVICES = root, www
+//! Host_Alias   SERVERS = build1, build2
+//! Cmnd_Alias   PKG = /usr/bin/pkg install *, /usr/bin/pkg update, /usr/bin/pkgclean ""
+//! ```
+//!
+//! Rules give users, on some hosts, commands to run as some targets:
+//!
+//! ```text
+//! root      ALL = (ALL:ALL) ALL
+//! %sudo     ALL = (ALL:ALL) ALL
+//! BUILDERS  ALL = (root) NOPASSWD: PKG, /bin/mount /dev/cdrom *, !/bin/mount -o *
+//! ```
+//!
+//! Users are given by name, groups as `%group` and `ALL` matches anything, any item
+//! can be negated with `!`. The run-as part lists the target users and, after a
+//! `:`, the target groups. Without it only root is allowed, with only a group list
+//! the command runs as the invoking user. The run-as part and the tags carry over
+//! to the next commands of the rule:
+//!
+//! - `NOPASSWD:` and `PASSWD:` waive or require the authentication of the user.
+//! - `LOG_INPUT:` and `LOG_OUTPUT:` run the command on a terminal of its own and
+//!   record what is typed or displayed, see `iolog`. `NOLOG_INPUT:` and
+//!   `NOLOG_OUTPUT:`, the default, turn that off again.
+//! - `SETENV:` lets the user pass any variable to the command, with `sudo -E`,
+//!   `--preserve-env` or `VAR=value` arguments. `NOSETENV:`, the default, only
+//!   lets the variables of `env_keep` through.
+//!
+//! Commands must be absolute paths, or `sudoedit` for the files that may be edited
+//! with `sudo -e`. A command alone allows any arguments, `""`
+//! allows none and anything else is a pattern matched against the arguments
+//! joined by spaces. In paths `*` and `?` do not match `/` and a path ending in `/`
+//! allows every command of that directory. A comma in an argument is written `\,`.
+//! The arguments of `sudoedit` are paths: each file is matched on its own against
+//! the paths listed, for example `sudoedit /etc/motd /etc/issue`.
+//!
+//! When several rules match a request the last one decides, so exceptions go after
+//! the general rules. When the file does not exist a built-in policy giving members
+//! of the `sudo` group everything is used.
+//!
+//! `Defaults` lines change settings, flags are set by name and cleared with `!`:
+//!
+//! ```text
+//! Defaults  timestamp_timeout=15, timestampdir=/var/run/sudo/ts
+//! ```
+//!
+//! - `timestamp_timeout`: minutes during which a successful authentication is
+//!   remembered for the user and terminal, `0` to always ask. Defaults to 5.
+//! - `timestampdir`: root-only directory holding those records.
+//! - `logfile`: file the audit records are appended to, see `audit`. Defaults to
+//!   `/var/log/sudo.log`.
+//! - `log_format`: `text` or `json`, the format of the audit records.
+//! - `iolog_dir`: root-only directory holding the recorded sessions. Defaults to
+//!   `/var/log/sudo-io`.
+//! - `secure_path`: directories, separated by `:`, commands given without a `/`
+//!   are looked for in. The caller's `PATH` is never used. Defaults to
+//!   `/sbin:/bin:/usr/sbin:/usr/bin`.
+//! - `env_keep`: variables, separated by spaces, kept from the caller's environment
+//!   on top of the `ENV_KEEP` of `/etc/login.defs`, which users may also set with
+//!   `--preserve-env` or `VAR=value` for any command, for example
+//!   `Defaults env_keep="http_proxy https_proxy"`.
+//! - `runcwd`: directory commands run in, instead of the caller's. `*` keeps the
+//!   caller's and lets users choose another one with `sudo -D`, which is refused
+//!   otherwise.
+//! - `umask`: octal mask added to the caller's umask for the command. Defaults to
+//!   `022`.
+//! - `allow_empty_password`: flag letting users who have no password set run
+//!   commands that need a password. Off by default, sudo then refuses them. Meant
+//!   for kiosk or development images, the decision is audited either way.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use audit::{AUDIT_LOG, AuditLog, LogFormat};
+use environ::DEFAULT_SUPATH;
+use iolog::{IOLOG_DIR, IoLog};
+
+mod eval;
+mod parse;
+
+pub use self::eval::{Decision, Identity, Request};
+
+/// Default location of the policy.
+pub const SUDOERS_FILE: &'static str = "/etc/sudoers";
+/// File holding the name of this host.
+pub const HOSTNAME_FILE: &'static str = "/etc/hostname";
+/// Default of the `timestamp_timeout` setting, in minutes.
+pub const TIMESTAMP_TIMEOUT: u64 = 5;
+/// Default of the `timestampdir` setting.
+pub const TIMESTAMP_DIR: &'static str = "/var/run/sudo/ts";
+/// Command under which the policy grants editing files with `sudo -e`.
+pub const SUDOEDIT: &'static str = "sudoedit";
+/// Default of the `umask` setting.
+pub const UMASK: u32 = 0o022;
+
+/// A user, group or host of a list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Item {
+    /// Matches anything.
+    All,
+    /// A user or host name.
+    Name(String),
+    /// The members of a group, written `%group`.
+    Group(String),
+    /// A reference to an alias of the list's kind.
+    Alias(String),
+}
+
+/// An entry of a user, run-as or host list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub negated: bool,
+    pub item: Item,
+}
+
+/// Arguments allowed for a command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Args {
+    /// No arguments were given in the policy, anything goes.
+    Any,
+    /// `""` was given, the command must be run without arguments.
+    Empty,
+    /// Pattern matched against the arguments joined by spaces.
+    Pattern(String),
+}
+
+/// A command of a rule or of a `Cmnd_Alias`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Cmnd {
+    /// Matches any command.
+    All,
+    /// A reference to a `Cmnd_Alias`.
+    Alias(String),
+    /// An absolute path, pattern or directory, with its allowed arguments. The path
+    /// is `sudoedit` for the files `sudo -e` may edit.
+    Path { path: String, args: Args },
+}
+
+/// An entry of a command list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CmndMember {
+    pub negated: bool,
+    pub cmnd: Cmnd,
+}
+
+/// Targets a command may be run as.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Runas {
+    /// Allowed target users, `None` when not given.
+    pub users: Option<Vec<Member>>,
+    /// Allowed target groups, `None` when not given.
+    pub groups: Option<Vec<Member>>,
+}
+
+/// Tags attached to a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tags {
+    /// Whether the invoking user has to authenticate.
+    pub passwd: bool,
+    /// Whether what the user types is recorded.
+    pub log_input: bool,
+    /// Whether what the command displays is recorded.
+    pub log_output: bool,
+    /// Whether the user may pass any variable to the command.
+    pub setenv: bool,
+}
+
+impl Default for Tags {
+    fn default() -> Tags {
+        Tags {
+            passwd: true,
+            log_input: false,
+            log_output: false,
+            setenv: false,
+        }
+    }
+}
+
+/// A command of a rule, along with the run-as part and tags that apply to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spec {
+    pub runas: Runas,
+    pub tags: Tags,
+    pub cmnd: CmndMember,
+}
+
+/// A user specification line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    /// Line the rule starts on.
+    pub line: usize,
+    pub users: Vec<Member>,
+    pub hosts: Vec<Member>,
+    pub specs: Vec<Spec>,
+}
+
+/// Value of a `Defaults` setting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Setting {
+    /// A flag, set by name or cleared with `!name`.
+    Flag(bool),
+    /// A setting given as `name=value`.
+    Value(String),
+}
+
+/// A parsed policy.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sudoers {
+    pub user_aliases: BTreeMap<String, Vec<Member>>,
+    pub runas_aliases: BTreeMap<String, Vec<Member>>,
+    pub host_aliases: BTreeMap<String, Vec<Member>>,
+    pub cmnd_aliases: BTreeMap<String, Vec<CmndMember>>,
+    pub defaults: BTreeMap<String, Setting>,
+    pub rules: Vec<Rule>,
+}
+
+impl Sudoers {
+    /// Loads the policy from `SUDOERS_FILE`.
+    ///
+    /// Falls back to `Sudoers::builtin` when the file does not exist.
+    pub fn load() -> io::Result<Sudoers> {
+        Sudoers::load_from(Path::new(SUDOERS_FILE))
+    }
+
+    /// Loads the policy from `path`, falling back to `Sudoers::builtin` when it
+    /// does not exist.
+    pub fn load_from(path: &Path) -> io::Result<Sudoers> {
+        let mut data = String::new();
+        match File::open(path) {
+            Ok(mut file) => { file.read_to_string(&mut data)?; },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Sudoers::builtin()),
+            Err(err) => return Err(err)
+        }
+
+        Sudoers::parse(&data).map_err(|(line, msg)| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}:{}: {}", path.display(), line, msg)
+        ))
+    }
+
+    /// Parses a policy, errors come with the line number they were found on.
+    pub fn parse(data: &str) -> Result<Sudoers, (usize, String)> {
+        parse::parse(data)
+    }
+
+    /// The policy used when there is no policy file, which lets root and the
+    /// members of the `sudo` group run anything as anyone.
+    pub fn builtin() -> Sudoers {
+        Sudoers::parse("root ALL = (ALL:ALL) ALL\n%sudo ALL = (ALL:ALL) ALL\n")
+            .expect("built-in policy is valid")
+    }
+
+    /// Whether the flag `name` is set.
+    pub fn flag(&self, name: &str) -> bool {
+        self.defaults.get(name) == Some(&Setting::Flag(true))
+    }
+
+    /// The value of the setting `name`, if set.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        match self.defaults.get(name) {
+            Some(&Setting::Value(ref value)) => Some(value),
+            _ => None
+        }
+    }
+
+    /// How long a successful authentication is remembered.
+    pub fn timestamp_timeout(&self) -> Duration {
+        let minutes = self.value("timestamp_timeout")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(TIMESTAMP_TIMEOUT);
+        Duration::from_secs(minutes * 60)
+    }
+
+    /// Directory holding the records of successful authentications.
+    pub fn timestamp_dir(&self) -> PathBuf {
+        PathBuf::from(self.value("timestampdir").unwrap_or(TIMESTAMP_DIR))
+    }
+
+    /// Where the audit records go.
+    pub fn audit_log(&self) -> AuditLog {
+        let format = self.value("log_format")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(LogFormat::Text);
+        AuditLog::new(self.value("logfile").unwrap_or(AUDIT_LOG), format)
+    }
+
+    /// The directories commands are looked for in, separated by `:`.
+    pub fn secure_path(&self) -> &str {
+        self.value("secure_path").unwrap_or(DEFAULT_SUPATH)
+    }
+
+    /// The variables of the `env_keep` setting.
+    pub fn env_keep(&self) -> Vec<&str> {
+        self.value("env_keep").map_or_else(Vec::new, |value| value.split_whitespace().collect())
+    }
+
+    /// The value of the `runcwd` setting, `*` when users may choose the directory.
+    pub fn run_cwd(&self) -> Option<&str> {
+        self.value("runcwd")
+    }
+
+    /// The mask added to the caller's umask for the command.
+    pub fn umask(&self) -> u32 {
+        self.value("umask")
+            .and_then(|value| u32::from_str_radix(value, 8).ok())
+            .unwrap_or(UMASK)
+    }
+
+    /// Whether users without a password may run commands that need one.
+    pub fn allow_empty_password(&self) -> bool {
+        self.flag("allow_empty_password")
+    }
+
+    /// Where the sessions are recorded.
+    pub fn io_log(&self) -> IoLog {
+        IoLog::new(self.value("iolog_dir").unwrap_or(IOLOG_DIR))
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Item::All => write!(f, "ALL"),
+            Item::Name(ref name) | Item::Alias(ref name) => write!(f, "{}", name),
+            Item::Group(ref group) => write!(f, "%{}", group)
+        }
+    }
+}
+
+impl fmt::Display for Member {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", if self.negated { "!" } else { "" }, self.item)
+    }
+}
+
+impl fmt::Display for Cmnd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cmnd::All => write!(f, "ALL"),
+            Cmnd::Alias(ref name) => write!(f, "{}", name),
+            Cmnd::Path { ref path, ref args } => {
+                // Commas separate commands, they are escaped to read back the same
+                let path = path.replace(',', "\\,");
+                match *args {
+                    Args::Any => write!(f, "{}", path),
+                    Args::Empty => write!(f, "{} \"\"", path),
+                    Args::Pattern(ref pattern) => write!(f, "{} {}", path, pattern.replace(',', "\\,"))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for CmndMember {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", if self.negated { "!" } else { "" }, self.cmnd)
+    }
+}
+
+impl fmt::Display for Tags {
+    /// Writes the tags that differ from the defaults, each followed by a space.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if ! self.passwd {
+            write!(f, "NOPASSWD: ")?;
+        }
+        if self.log_input {
+            write!(f, "LOG_INPUT: ")?;
+        }
+        if self.log_output {
+            write!(f, "LOG_OUTPUT: ")?;
+        }
+        if self.setenv {
+            write!(f, "SETENV: ")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Runas {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn list(members: &[Member]) -> String {
+            members.iter().map(|member| member.to_string()).collect::<Vec<String>>().join(", ")
+        }
+
+        match (self.users.as_ref(), self.groups.as_ref()) {
+            (Some(users), Some(groups)) => write!(f, "({} : {})", list(users), list(groups)),
+            (Some(users), None) => write!(f, "({})", list(users)),
+            (None, Some(groups)) => write!(f, "(: {})", list(groups)),
+            (None, None) => write!(f, "(root)")
+        }
+    }
+}
+
+/// The name of this host, as matched by host lists.
+pub fn hostname() -> String {
+    let mut name = String::new();
+    match File::open(HOSTNAME_FILE).and_then(|mut file| file.read_to_string(&mut name)) {
+        Ok(_) if ! name.trim().is_empty() => name.trim().to_string(),
+        _ => "localhost".to_string()
+    }
+}
@@ -0,0 +1,479 @@
+//! Parser of the policy file.
+
+use std::collections::BTreeMap;
+
+use environ::is_var_name;
+
+use super::{Args, Cmnd, CmndMember, Item, Member, Rule, Runas, SUDOEDIT, Setting, Spec, Sudoers, Tags};
+
+type ParseResult<T> = Result<T, String>;
+
+/// What a setting accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Set by name, cleared with `!`.
+    Flag,
+    /// A non negative integer.
+    Number,
+    /// An absolute path.
+    Path,
+    /// Absolute paths separated by `:`.
+    PathList,
+    /// Names of environment variables separated by spaces.
+    VarList,
+    /// An absolute path or `*`.
+    Directory,
+    /// An octal file mode creation mask.
+    Mask,
+    /// One of the given words.
+    Choice(&'static [&'static str]),
+}
+
+/// Settings accepted in `Defaults` lines.
+const SETTINGS: &'static [(&'static str, Kind)] = &[
+    ("timestamp_timeout", Kind::Number),
+    ("timestampdir", Kind::Path),
+    ("logfile", Kind::Path),
+    ("log_format", Kind::Choice(&["text", "json"])),
+    ("iolog_dir", Kind::Path),
+    ("secure_path", Kind::PathList),
+    ("env_keep", Kind::VarList),
+    ("runcwd", Kind::Directory),
+    ("umask", Kind::Mask),
+    ("allow_empty_password", Kind::Flag),
+];
+
+pub fn parse(data: &str) -> Result<Sudoers, (usize, String)> {
+    let mut sudoers = Sudoers::default();
+
+    for (line, text) in logical_lines(data) {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        parse_line(&mut sudoers, line, text).map_err(|msg| (line, msg))?;
+    }
+
+    Ok(sudoers)
+}
+
+/// Joins the lines ending with `\` to the next ones, keeping the number of the
+/// first line of each.
+fn logical_lines(data: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+
+    for (i, line) in data.lines().enumerate() {
+        let (start, mut text) = current.take().unwrap_or((i + 1, String::new()));
+        let trailing = line.len() - line.trim_end_matches('\\').len();
+        if trailing % 2 == 1 {
+            text.push_str(&line[..line.len() - 1]);
+            text.push(' ');
+            current = Some((start, text));
+        } else {
+            text.push_str(line);
+            lines.push((start, text));
+        }
+    }
+
+    if let Some(last) = current {
+        lines.push(last);
+    }
+
+    lines
+}
+
+fn parse_line(sudoers: &mut Sudoers, line: usize, text: &str) -> ParseResult<()> {
+    let keyword = text.split_whitespace().next().unwrap_or("");
+    let rest = text[keyword.len()..].trim();
+
+    match keyword {
+        "Defaults" => {
+            for item in split_top(rest, ',')? {
+                let (name, setting) = parse_setting(item.trim())?;
+                sudoers.defaults.insert(name, setting);
+            }
+        },
+        "User_Alias" => {
+            let (name, value) = parse_alias(rest, &sudoers.user_aliases)?;
+            let members = parse_members(&value, &sudoers.user_aliases)?;
+            sudoers.user_aliases.insert(name, members);
+        },
+        "Runas_Alias" => {
+            let (name, value) = parse_alias(rest, &sudoers.runas_aliases)?;
+            let members = parse_members(&value, &sudoers.runas_aliases)?;
+            sudoers.runas_aliases.insert(name, members);
+        },
+        "Host_Alias" => {
+            let (name, value) = parse_alias(rest, &sudoers.host_aliases)?;
+            let members = parse_members(&value, &sudoers.host_aliases)?;
+            sudoers.host_aliases.insert(name, members);
+        },
+        "Cmnd_Alias" => {
+            let (name, value) = parse_alias(rest, &sudoers.cmnd_aliases)?;
+            let mut members = Vec::new();
+            for item in split_top(&value, ',')? {
+                members.push(parse_cmnd(&item, &sudoers.cmnd_aliases)?);
+            }
+            sudoers.cmnd_aliases.insert(name, members);
+        },
+        _ => {
+            let rule = parse_rule(sudoers, line, text)?;
+            sudoers.rules.push(rule);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_setting(text: &str) -> ParseResult<(String, Setting)> {
+    let (name, setting) = if text.starts_with('!') {
+        (text[1..].trim(), Setting::Flag(false))
+    } else if let Some(eq) = text.find('=') {
+        let value = text[eq + 1..].trim().trim_matches('"');
+        (text[..eq].trim(), Setting::Value(value.to_string()))
+    } else {
+        (text, Setting::Flag(true))
+    };
+
+    let kind = match SETTINGS.iter().find(|&&(known, _)| known == name) {
+        Some(&(_, kind)) => kind,
+        None => return Err(format!("unknown setting '{}'", name))
+    };
+
+    let valid = match (kind, &setting) {
+        (Kind::Flag, &Setting::Flag(_)) => true,
+        (Kind::Number, &Setting::Value(ref value)) => value.parse::<u64>().is_ok(),
+        (Kind::Path, &Setting::Value(ref value)) => value.starts_with('/'),
+        (Kind::PathList, &Setting::Value(ref value)) => value.split(':').all(|path| path.starts_with('/')),
+        (Kind::VarList, &Setting::Value(ref value)) => value.split_whitespace().all(is_var_name),
+        (Kind::Directory, &Setting::Value(ref value)) => value == "*" || value.starts_with('/'),
+        (Kind::Mask, &Setting::Value(ref value)) => u32::from_str_radix(value, 8).map_or(false, |mask| mask <= 0o777),
+        (Kind::Choice(words), &Setting::Value(ref value)) => words.contains(&value.as_str()),
+        _ => false
+    };
+    if ! valid {
+        return Err(format!("invalid value for setting '{}'", name));
+    }
+
+    Ok((name.to_string(), setting))
+}
+
+/// Splits `NAME = value`, checking that `NAME` is free.
+fn parse_alias<T>(text: &str, defined: &BTreeMap<String, T>) -> ParseResult<(String, String)> {
+    let eq = text.find('=').ok_or_else(|| "expected 'NAME = list'".to_string())?;
+    let name = text[..eq].trim();
+    if ! is_alias_name(name) {
+        return Err(format!("invalid alias name '{}'", name));
+    }
+    if defined.contains_key(name) {
+        return Err(format!("alias '{}' is already defined", name));
+    }
+
+    Ok((name.to_string(), text[eq + 1..].to_string()))
+}
+
+fn parse_rule(sudoers: &Sudoers, line: usize, text: &str) -> ParseResult<Rule> {
+    let eq = text.find('=').ok_or_else(|| "expected 'users hosts = commands'".to_string())?;
+
+    let lists = join_lists(&text[..eq]);
+    if lists.len() != 2 {
+        return Err("expected 'users hosts = commands'".to_string());
+    }
+    let users = parse_members(&lists[0], &sudoers.user_aliases)?;
+    let hosts = parse_members(&lists[1], &sudoers.host_aliases)?;
+
+    let mut runas = Runas::default();
+    let mut tags = Tags::default();
+    let mut specs = Vec::new();
+    for item in split_top(&text[eq + 1..], ',')? {
+        let mut item = item.trim();
+
+        if item.starts_with('(') {
+            let end = item.find(')').ok_or_else(|| "missing ')' in run-as list".to_string())?;
+            runas = parse_runas(&item[1..end], sudoers)?;
+            item = item[end + 1..].trim_start();
+        }
+
+        loop {
+            let word = item.split_whitespace().next().unwrap_or("");
+            if ! word.ends_with(':') || ! word[..word.len() - 1].chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+                break;
+            }
+
+            match &word[..word.len() - 1] {
+                "NOPASSWD" => tags.passwd = false,
+                "PASSWD" => tags.passwd = true,
+                "LOG_INPUT" => tags.log_input = true,
+                "NOLOG_INPUT" => tags.log_input = false,
+                "LOG_OUTPUT" => tags.log_output = true,
+                "NOLOG_OUTPUT" => tags.log_output = false,
+                "SETENV" => tags.setenv = true,
+                "NOSETENV" => tags.setenv = false,
+                tag => return Err(format!("unknown tag '{}'", tag))
+            }
+            item = item[word.len()..].trim_start();
+        }
+
+        specs.push(Spec {
+            runas: runas.clone(),
+            tags: tags,
+            cmnd: parse_cmnd(item, &sudoers.cmnd_aliases)?,
+        });
+    }
+
+    Ok(Rule {
+        line: line,
+        users: users,
+        hosts: hosts,
+        specs: specs,
+    })
+}
+
+fn parse_runas(text: &str, sudoers: &Sudoers) -> ParseResult<Runas> {
+    let mut parts = text.splitn(2, ':');
+    let users = parts.next().unwrap_or("").trim();
+    let groups = parts.next().map(|groups| groups.trim());
+
+    let runas = Runas {
+        users: if users.is_empty() {
+            None
+        } else {
+            Some(parse_members(users, &sudoers.runas_aliases)?)
+        },
+        groups: match groups {
+            Some(groups) if ! groups.is_empty() => Some(parse_members(groups, &sudoers.runas_aliases)?),
+            _ => None
+        },
+    };
+
+    if runas.users.is_none() && runas.groups.is_none() {
+        return Err("empty run-as list".to_string());
+    }
+
+    Ok(runas)
+}
+
+fn parse_members<T>(text: &str, aliases: &BTreeMap<String, T>) -> ParseResult<Vec<Member>> {
+    let mut members = Vec::new();
+    for item in text.split(',') {
+        let mut item = item.trim();
+        let mut negated = false;
+        while item.starts_with('!') {
+            negated = ! negated;
+            item = item[1..].trim_start();
+        }
+
+        let item = if item.is_empty() {
+            return Err("empty list item".to_string());
+        } else if item == "ALL" {
+            Item::All
+        } else if item.starts_with('%') && item.len() > 1 {
+            Item::Group(item[1..].to_string())
+        } else if is_alias_name(item) {
+            if ! aliases.contains_key(item) {
+                return Err(format!("alias '{}' used before being defined", item));
+            }
+            Item::Alias(item.to_string())
+        } else if item.chars().all(|c| c.is_alphanumeric() || "-_.".contains(c)) {
+            Item::Name(item.to_string())
+        } else {
+            return Err(format!("invalid list item '{}'", item));
+        };
+
+        members.push(Member {
+            negated: negated,
+            item: item,
+        });
+    }
+
+    Ok(members)
+}
+
+fn parse_cmnd(text: &str, aliases: &BTreeMap<String, Vec<CmndMember>>) -> ParseResult<CmndMember> {
+    let mut text = text.trim();
+    let mut negated = false;
+    while text.starts_with('!') {
+        negated = ! negated;
+        text = text[1..].trim_start();
+    }
+
+    let words = text.split_whitespace().collect::<Vec<&str>>();
+    let cmnd = match words.first() {
+        None => return Err("missing command".to_string()),
+        Some(&"ALL") if words.len() == 1 => Cmnd::All,
+        Some(word) if is_alias_name(word) && words.len() == 1 => {
+            if ! aliases.contains_key(*word) {
+                return Err(format!("alias '{}' used before being defined", word));
+            }
+            Cmnd::Alias(word.to_string())
+        },
+        Some(word) if word.starts_with('/') || *word == SUDOEDIT => Cmnd::Path {
+            path: word.to_string(),
+            args: match &words[1..] {
+                [] => Args::Any,
+                ["\"\""] => Args::Empty,
+                args => Args::Pattern(args.join(" "))
+            },
+        },
+        Some(word) => return Err(format!("command '{}' is not an absolute path", word))
+    };
+
+    Ok(CmndMember {
+        negated: negated,
+        cmnd: cmnd,
+    })
+}
+
+/// Splits the `users hosts` part of a rule, allowing spaces around the commas of
+/// each list.
+fn join_lists(text: &str) -> Vec<String> {
+    let mut lists: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        let continues = match lists.last() {
+            Some(last) => last.ends_with(',') || word.starts_with(','),
+            None => false
+        };
+
+        if continues {
+            lists.last_mut().unwrap().push_str(word);
+        } else {
+            lists.push(word.to_string());
+        }
+    }
+    lists
+}
+
+/// Splits `text` on the `sep` found outside of parentheses, unescaping `\,`, `\:`,
+/// `\=` and `\\`.
+fn split_top(text: &str, sep: char) -> ParseResult<Vec<String>> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if ",:=\\".contains(next) => current.push(next),
+                Some(next) => {
+                    current.push(c);
+                    current.push(next);
+                },
+                None => current.push(c)
+            },
+            '(' => {
+                depth += 1;
+                current.push(c);
+            },
+            ')' => {
+                if depth == 0 {
+                    return Err("unbalanced ')'".to_string());
+                }
+                depth -= 1;
+                current.push(c);
+            },
+            _ if c == sep && depth == 0 => items.push(current.split_off(0)),
+            _ => current.push(c)
+        }
+    }
+
+    if depth != 0 {
+        return Err("missing ')'".to_string());
+    }
+    items.push(current);
+
+    Ok(items)
+}
+
+fn is_alias_name(name: &str) -> bool {
+    name != "ALL"
+        && name.chars().next().map_or(false, |c| c.is_ascii_uppercase())
+        && name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_must_be_defined_first() {
+        assert_eq!(parse("ADMINS ALL = ALL\n").unwrap_err().0, 1);
+        assert!(parse("User_Alias ADMINS = alice\nADMINS ALL = ALL\n").is_ok());
+        assert!(parse("User_Alias ADMINS = alice\nUser_Alias ADMINS = bob\n").is_err());
+        assert!(parse("Cmnd_Alias PKG = /usr/bin/pkg\nalice ALL = PKG\n").is_ok());
+    }
+
+    #[test]
+    fn members_and_negation() {
+        let sudoers = parse("User_Alias A = alice, !%staff, !!bob\n").unwrap();
+        let members = &sudoers.user_aliases["A"];
+        assert_eq!(members[0], Member { negated: false, item: Item::Name("alice".to_string()) });
+        assert_eq!(members[1], Member { negated: true, item: Item::Group("staff".to_string()) });
+        assert_eq!(members[2], Member { negated: false, item: Item::Name("bob".to_string()) });
+        assert!(parse("User_Alias A = alice,\n").is_err());
+    }
+
+    #[test]
+    fn runas_lists() {
+        let sudoers = parse("alice ALL = (www, db : staff) /bin/ls, /bin/cat\nbob ALL = (:staff) /bin/id\n").unwrap();
+        let specs = &sudoers.rules[0].specs;
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].runas, specs[1].runas);
+        assert_eq!(specs[0].runas.users.as_ref().map(|users| users.len()), Some(2));
+        assert_eq!(specs[0].runas.groups.as_ref().map(|groups| groups.len()), Some(1));
+        assert!(sudoers.rules[1].specs[0].runas.users.is_none());
+        assert!(parse("alice ALL = () /bin/ls\n").is_err());
+        assert!(parse("alice ALL = (root /bin/ls\n").is_err());
+    }
+
+    #[test]
+    fn commands_and_arguments() {
+        let sudoers = parse("alice ALL = /bin/ls, /bin/cat \"\", /usr/bin/pkg install *, !/bin/su, sudoedit /etc/motd\n").unwrap();
+        let cmnds = sudoers.rules[0].specs.iter().map(|spec| &spec.cmnd).collect::<Vec<&CmndMember>>();
+        assert_eq!(cmnds[0].cmnd, Cmnd::Path { path: "/bin/ls".to_string(), args: Args::Any });
+        assert_eq!(cmnds[1].cmnd, Cmnd::Path { path: "/bin/cat".to_string(), args: Args::Empty });
+        assert_eq!(cmnds[2].cmnd, Cmnd::Path { path: "/usr/bin/pkg".to_string(), args: Args::Pattern("install *".to_string()) });
+        assert!(cmnds[3].negated);
+        assert_eq!(cmnds[4].cmnd, Cmnd::Path { path: SUDOEDIT.to_string(), args: Args::Pattern("/etc/motd".to_string()) });
+        assert!(parse("alice ALL = ls\n").is_err());
+        assert!(parse("alice ALL = /bin/echo a\\,b\n").map(|sudoers| sudoers.rules[0].specs.len()) == Ok(1));
+    }
+
+    #[test]
+    fn tags() {
+        let sudoers = parse("alice ALL = NOPASSWD: LOG_OUTPUT: /bin/ls, SETENV: /bin/env, PASSWD: NOLOG_OUTPUT: /bin/cat\n").unwrap();
+        let tags = sudoers.rules[0].specs.iter().map(|spec| spec.tags).collect::<Vec<Tags>>();
+        assert_eq!(tags[0], Tags { passwd: false, log_input: false, log_output: true, setenv: false });
+        assert_eq!(tags[1], Tags { passwd: false, log_input: false, log_output: true, setenv: true });
+        assert_eq!(tags[2], Tags { passwd: true, log_input: false, log_output: false, setenv: true });
+        assert!(parse("alice ALL = NOPE: /bin/ls\n").is_err());
+    }
+
+    #[test]
+    fn defaults_are_validated() {
+        let sudoers = parse("Defaults timestamp_timeout=15, log_format=json, env_keep=\"A B\", allow_empty_password\n").unwrap();
+        assert_eq!(sudoers.defaults["timestamp_timeout"], Setting::Value("15".to_string()));
+        assert_eq!(sudoers.defaults["env_keep"], Setting::Value("A B".to_string()));
+        assert_eq!(sudoers.defaults["allow_empty_password"], Setting::Flag(true));
+        assert!(parse("Defaults timestamp_timeout=soon\n").is_err());
+        assert!(parse("Defaults logfile=relative\n").is_err());
+        assert!(parse("Defaults log_format=xml\n").is_err());
+        assert!(parse("Defaults secure_path=/bin:bin\n").is_err());
+        assert!(parse("Defaults env_keep=\"A-B\"\n").is_err());
+        assert!(parse("Defaults umask=0999\n").is_err());
+        assert!(parse("Defaults runcwd=*\n").is_ok());
+        assert!(parse("Defaults umask\n").is_err());
+        assert!(parse("Defaults no_such_setting\n").is_err());
+    }
+
+    #[test]
+    fn continued_lines_keep_their_first_line_number() {
+        let sudoers = parse("# policy\n\nalice ALL = /bin/ls, \\\n    /bin/cat\nbob ALL = ALL\n").unwrap();
+        assert_eq!(sudoers.rules[0].line, 3);
+        assert_eq!(sudoers.rules[0].specs.len(), 2);
+        assert_eq!(sudoers.rules[1].line, 5);
+        assert_eq!(parse("alice ALL = /bin/ls\nbroken\n").unwrap_err().0, 2);
+    }
+}
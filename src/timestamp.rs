@@ -0,0 +1,290 @@
+//! Records of successful `sudo` authentications.
+//!
+//! Each user has a file named after it in the timestamp directory, holding one
+//! `tty;time` line per terminal it authenticated on, `time` being in seconds since
+//! the UNIX epoch. A record only lets the user through on the same terminal and
+//! while it is younger than the timeout. Records dated in the future, which means
+//! the clock went backwards since they were written, are ignored.
+//!
+//! The directory is created readable by root only. Records found in a directory
+//! that is not owned by root, or that others may write to, are never trusted.
+
+use std::fmt;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use db::{read_entries, write_entries};
+
+/// The authentication of a user on a terminal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Path of the terminal.
+    pub tty: String,
+    /// Time of the authentication in seconds since the UNIX epoch, `0` once
+    /// invalidated.
+    pub time: u64,
+}
+
+impl Record {
+    /// Parses a record line.
+    pub fn parse(line: &str) -> Option<Record> {
+        let mut parts = line.rsplitn(2, ';');
+        let time = parts.next()?.parse().ok()?;
+        let tty = parts.next()?;
+
+        Some(Record {
+            tty: tty.to_string(),
+            time: time,
+        })
+    }
+
+    /// Whether the record lets its user through at `now`.
+    pub fn is_valid(&self, now: u64, timeout: Duration) -> bool {
+        self.time != 0 && self.time <= now && now - self.time < timeout.as_secs()
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{};{}", self.tty, self.time)
+    }
+}
+
+/// The timestamp directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timestamps {
+    dir: PathBuf,
+}
+
+impl Timestamps {
+    /// Uses the records of `dir`.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Timestamps {
+        Timestamps { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// Whether `user` authenticated on `tty` less than `timeout` ago.
+    pub fn is_valid(&self, user: &str, tty: &str, timeout: Duration) -> bool {
+        if ! self.is_secure() {
+            return false;
+        }
+
+        let now = now();
+        self.records(user)
+            .map(|records| records.iter().any(|record| record.tty == tty && record.is_valid(now, timeout)))
+            .unwrap_or(false)
+    }
+
+    /// Records that `user` just authenticated on `tty`.
+    pub fn update(&self, user: &str, tty: &str) -> io::Result<()> {
+        self.create_dir()?;
+
+        let mut records = self.records(user).unwrap_or_default();
+        records.retain(|record| record.tty != tty);
+        records.push(Record {
+            tty: tty.to_string(),
+            time: now(),
+        });
+
+        write_entries(&self.path(user), &records, 0o600)
+    }
+
+    /// Invalidates the records of `user` on every terminal.
+    pub fn invalidate(&self, user: &str) -> io::Result<()> {
+        let mut records = match self.records(user) {
+            Ok(records) => records,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err)
+        };
+
+        for record in records.iter_mut() {
+            record.time = 0;
+        }
+
+        write_entries(&self.path(user), &records, 0o600)
+    }
+
+    /// Removes the records of `user` altogether.
+    pub fn remove(&self, user: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(user)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result
+        }
+    }
+
+    fn records(&self, user: &str) -> io::Result<Vec<Record>> {
+        read_entries(&self.path(user), Record::parse)
+    }
+
+    fn path(&self, user: &str) -> PathBuf {
+        self.dir.join(user)
+    }
+
+    fn create_dir(&self) -> io::Result<()> {
+        if ! self.dir.exists() {
+            DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+        }
+
+        if self.is_secure() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not a directory owned by root and only writable by it", self.dir.display())
+            ))
+        }
+    }
+
+    fn is_secure(&self) -> bool {
+        match fs::metadata(&self.dir) {
+            Ok(metadata) => metadata.is_dir() && metadata.uid() == 0 && metadata.mode() & 0o022 == 0,
+            Err(_) => false
+        }
+    }
+}
+
+/// Seconds since the UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    const TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// A throwaway timestamp directory. `None` unless running as root, the only
+    /// owner whose records are trusted.
+    fn timestamps() -> Option<Timestamps> {
+        let dir = env::temp_dir().join(format!("userutils-ts-{}-{}", ::std::process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir).unwrap();
+        if fs::metadata(&dir).unwrap().uid() != 0 {
+            return None;
+        }
+        Some(Timestamps::new(dir))
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let record = Record::parse("/scheme/pty/1;1500000000").unwrap();
+        assert_eq!(record, Record { tty: "/scheme/pty/1".to_string(), time: 1500000000 });
+        assert_eq!(record.to_string(), "/scheme/pty/1;1500000000");
+
+        // Only the last field is the time
+        assert_eq!(Record::parse("a;b;12").unwrap().tty, "a;b");
+        assert_eq!(Record::parse("tty"), None);
+        assert_eq!(Record::parse("tty;soon"), None);
+    }
+
+    #[test]
+    fn records_expire() {
+        let record = Record { tty: "tty".to_string(), time: 1000 };
+        assert!(record.is_valid(1000, TIMEOUT));
+        assert!(record.is_valid(1299, TIMEOUT));
+        assert!(! record.is_valid(1300, TIMEOUT));
+        assert!(! record.is_valid(1000, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn records_from_the_future_are_ignored() {
+        let record = Record { tty: "tty".to_string(), time: 1000 };
+        assert!(! record.is_valid(999, TIMEOUT));
+        assert!(! record.is_valid(0, TIMEOUT));
+    }
+
+    #[test]
+    fn invalidated_records_are_ignored() {
+        let record = Record { tty: "tty".to_string(), time: 0 };
+        assert!(! record.is_valid(0, TIMEOUT));
+        assert!(! record.is_valid(10, TIMEOUT));
+    }
+
+    #[test]
+    fn records_only_count_on_their_terminal() {
+        let timestamps = match timestamps() {
+            Some(timestamps) => timestamps,
+            None => return
+        };
+
+        assert!(! timestamps.is_valid("alice", "pty:1", TIMEOUT));
+        timestamps.update("alice", "pty:1").unwrap();
+        assert!(timestamps.is_valid("alice", "pty:1", TIMEOUT));
+        assert!(! timestamps.is_valid("alice", "pty:2", TIMEOUT));
+        assert!(! timestamps.is_valid("bob", "pty:1", TIMEOUT));
+
+        // Updating one terminal leaves the others alone
+        timestamps.update("alice", "pty:2").unwrap();
+        timestamps.update("alice", "pty:2").unwrap();
+        assert!(timestamps.is_valid("alice", "pty:1", TIMEOUT));
+        assert_eq!(timestamps.records("alice").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn records_written_before_the_clock_went_backwards_are_ignored() {
+        let timestamps = match timestamps() {
+            Some(timestamps) => timestamps,
+            None => return
+        };
+
+        fs::write(timestamps.path("alice"), format!("pty:1;{}\n", now() + 3600)).unwrap();
+        assert!(! timestamps.is_valid("alice", "pty:1", TIMEOUT));
+
+        fs::write(timestamps.path("alice"), format!("pty:1;{}\n", now() - 10)).unwrap();
+        assert!(timestamps.is_valid("alice", "pty:1", TIMEOUT));
+        assert!(! timestamps.is_valid("alice", "pty:1", Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn invalidate_and_remove() {
+        let timestamps = match timestamps() {
+            Some(timestamps) => timestamps,
+            None => return
+        };
+
+        // Nothing to do for users without records
+        timestamps.invalidate("alice").unwrap();
+        timestamps.remove("alice").unwrap();
+
+        timestamps.update("alice", "pty:1").unwrap();
+        timestamps.update("alice", "pty:2").unwrap();
+        timestamps.update("bob", "pty:1").unwrap();
+        timestamps.invalidate("alice").unwrap();
+        assert!(! timestamps.is_valid("alice", "pty:1", TIMEOUT));
+        assert!(! timestamps.is_valid("alice", "pty:2", TIMEOUT));
+        assert!(timestamps.is_valid("bob", "pty:1", TIMEOUT));
+        assert!(timestamps.path("alice").exists());
+
+        timestamps.update("alice", "pty:1").unwrap();
+        assert!(timestamps.is_valid("alice", "pty:1", TIMEOUT));
+
+        timestamps.remove("alice").unwrap();
+        assert!(! timestamps.path("alice").exists());
+        assert!(! timestamps.is_valid("alice", "pty:1", TIMEOUT));
+        assert!(timestamps.is_valid("bob", "pty:1", TIMEOUT));
+    }
+
+    #[test]
+    fn records_in_a_writable_directory_are_not_trusted() {
+        let timestamps = match timestamps() {
+            Some(timestamps) => timestamps,
+            None => return
+        };
+
+        timestamps.update("alice", "pty:1").unwrap();
+        fs::set_permissions(&timestamps.dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(! timestamps.is_valid("alice", "pty:1", TIMEOUT));
+        assert!(timestamps.update("alice", "pty:1").is_err());
+    }
+}